@@ -0,0 +1,26 @@
+use std::fs;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Everything `finit` is built from, which could change how it talks to us.
+const INIT_SOURCES: &[&str] = &[
+    "src/bin/finit.rs",
+    "src/namespace/child.rs",
+    "src/namespace/net.rs",
+];
+
+/// Derive the init protocol version from its sources, for `child::HANDSHAKE`, so an init
+/// built from any other checkout is rejected, without anyone having to remember to bump it.
+fn main() {
+    let mut hasher = Sha256::new();
+    for path in INIT_SOURCES {
+        println!("cargo:rerun-if-changed={}", path);
+        let source = fs::read(path).unwrap_or_else(|e| panic!("reading {}: {}", path, e));
+        hasher.input(path.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(&source);
+    }
+    let protocol = format!("{:x}", hasher.result());
+    println!("cargo:rustc-env=FAPPA_INIT_PROTOCOL={}", &protocol[..16]);
+}
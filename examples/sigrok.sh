@@ -26,8 +26,6 @@ git-export 'git://developer.intra2net.com/libftdi' master d5c1622a2ff0c722c0dc59
 
 fappa-cmake
 
-sudo make install
-
 export DEPENDS=a
 
 fappa-package
@@ -1,13 +1,45 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
 use anyhow::anyhow;
+use anyhow::ensure;
 use anyhow::Error;
 use anyhow::Context;
+use log::error;
 use log::info;
 
 use fappa::build;
+use fappa::cache;
 use fappa::fetch_images;
 use fappa::git;
+use fappa::graph;
+use fappa::logs;
 use fappa::namespace;
+use fappa::namespace::cgroup;
+use fappa::namespace::cgroup::Limits;
+use fappa::namespace::config::Config;
+use fappa::namespace::config::Network;
+use fappa::patch;
+use fappa::repo;
+use fappa::repro;
+use fappa::sources;
 use fappa::specs;
+use fappa::schedule::Job;
+use fappa::schedule::Schedule;
+use fappa::schedule::State;
+use fappa::Release;
 use fappa::RELEASES;
 
 fn main() -> Result<(), Error> {
@@ -20,7 +52,36 @@ fn main() -> Result<(), Error> {
     let matches = clap::App::new("fappa")
         .setting(clap::AppSettings::SubcommandRequiredElseHelp)
         .subcommand(SubCommand::with_name("validate"))
-        .subcommand(SubCommand::with_name("build"))
+        .subcommand(
+            SubCommand::with_name("build")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("rebuild even if there's a cached result"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .takes_value(true)
+                        .help("how many builds to run at once"),
+                )
+                .arg(
+                    Arg::with_name("sign-key")
+                        .long("sign-key")
+                        .takes_value(true)
+                        .help("gpg key to sign the repository's InRelease with"),
+                )
+                .args(&option_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("build-one")
+                .setting(clap::AppSettings::Hidden)
+                .arg(Arg::with_name("package").required(true))
+                .arg(Arg::with_name("release").required(true))
+                .arg(Arg::with_name("key").required(true))
+                .args(&option_args()),
+        )
         .subcommand(
             SubCommand::with_name("namespace")
                 .arg(
@@ -29,44 +90,284 @@ fn main() -> Result<(), Error> {
                         .required(true)
                         .takes_value(true),
                 )
-                .arg(Arg::with_name("root").short("r")),
+                .arg(Arg::with_name("root").short("r"))
+                .arg(
+                    Arg::with_name("bind")
+                        .long("bind")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("mount a host path inside, as /host:/inside[:ro|:rw]"),
+                )
+                .arg(
+                    Arg::with_name("tmpfs")
+                        .long("tmpfs")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("mount an empty tmpfs inside, replacing the default of /tmp"),
+                )
+                .arg(
+                    Arg::with_name("no-dev")
+                        .long("no-dev")
+                        .help("use the image's /dev, with only /dev/null bound in"),
+                )
+                .arg(
+                    Arg::with_name("host-network")
+                        .long("host-network")
+                        .help("share the host's network and DNS, instead of just a loopback"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-repro")
+                .about("build a package twice, and compare the results")
+                .arg(Arg::with_name("package").required(true))
+                .arg(Arg::with_name("release").required(true))
+                .args(&option_args()),
         )
         .subcommand(SubCommand::with_name("fetch"))
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("show the log of the latest, or a specific, build")
+                .arg(Arg::with_name("package").required(true))
+                .arg(Arg::with_name("release").required(true))
+                .arg(Arg::with_name("run")),
+        )
         .get_matches();
 
     match matches.subcommand() {
         ("validate", _) => {
-            for package in specs::load_from("specs")? {
-                for command in package.source() {
+            for package in graph::build_order(specs::load_from("specs")?)? {
+                fetch_sources(dirs.cache_dir(), &package)?;
+                for command in &package.source {
                     match command {
-                        specs::Command::Clone { repo, .. } => git::check_cloned(repo)?,
+                        specs::Command::Local { path, .. } => {
+                            sources::local(&package.dir, path)?;
+                        }
+                        specs::Command::Patches { dir, .. } => {
+                            patch::load_series(package.dir.join(dir))?;
+                        }
                         _ => continue,
                     };
                 }
             }
         }
-        ("build", _) => {
-            for package in specs::load_from("specs")? {
-                for release in &RELEASES {
-                    build::build(release, &package)?;
+        ("build", Some(matches)) => {
+            let force = matches.is_present("force");
+            let jobs: usize = match matches.value_of("jobs") {
+                Some(jobs) => jobs.parse()?,
+                None => (num_cpus::get() / 2).max(1),
+            };
+            ensure!(jobs > 0, "at least one job is required");
+            let mut options = options(matches)?;
+            options.limits.validate()?;
+            if !options.limits.is_empty() {
+                // once, here, as it moves us, and so the workers, out of the way
+                options.limits.parent = Some(cgroup::prepare(&options.limits)?);
+            }
+            let sign_key = matches.value_of("sign-key");
+
+            let cache = dirs.cache_dir();
+            // so the results can be installed as soon as they're ready; if they can't be,
+            // the job has failed, as anything depending on it would get the old ones
+            let publish = |release: Release, done: State| match repo::update(
+                &build::debs_dir(cache, &release),
+                release.codename(),
+                sign_key,
+            ) {
+                Ok(()) => done,
+                Err(e) => {
+                    error!("{}: publishing: {:?}", release.codename(), e);
+                    State::Failed
                 }
+            };
+            let packages = graph::build_order(specs::load_from("specs")?)?;
+            let mut schedule = Schedule::new(&packages, &RELEASES);
+
+            // every release's build of a package shares its git clone and downloads,
+            // so fill the caches before any workers start, instead of letting them race
+            let mut unfetched = HashSet::new();
+            for package in &packages {
+                if let Err(e) = fetch_sources(cache, package) {
+                    error!("{}: fetching sources: {:?}", package.name, e);
+                    unfetched.insert(package.name.as_str());
+                }
+            }
+
+            let mut images = HashMap::new();
+            let mut built: HashMap<Job, Vec<PathBuf>> = HashMap::new();
+            let (mut hits, mut misses) = (0, 0);
+            let (send, recv) = mpsc::channel();
+            let mut running = 0;
+
+            loop {
+                while running < jobs {
+                    let job = match schedule.next() {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let package = schedule.package(job);
+                    let release = schedule.release(job);
+                    if unfetched.contains(package.name.as_str()) {
+                        schedule.finish(job, State::Failed);
+                        continue;
+                    }
+
+                    let dep_debs: Vec<PathBuf> = schedule
+                        .deps(job)
+                        .flat_map(|dep| built[&dep].clone())
+                        .collect();
+
+                    // other builds may be running, so a failure here is just this job's
+                    let key = image_sha256(cache, release, &mut images).and_then(|image| {
+                        cache::key(package, &release, &image, &dep_debs, options.reproducible)
+                    });
+                    let looked_up = key.and_then(|key| {
+                        let debs = if force {
+                            None
+                        } else {
+                            cache::lookup(cache, &key)?
+                        };
+                        Ok((key, debs))
+                    });
+                    let key = match looked_up {
+                        Ok((_, Some(debs))) => {
+                            info!("{} on {}: cached", package.name, release.codename());
+                            hits += 1;
+                            built.insert(job, debs);
+                            schedule.finish(job, publish(release, State::Cached));
+                            continue;
+                        }
+                        Ok((key, None)) => key,
+                        Err(e) => {
+                            error!("{} on {}: {:?}", package.name, release.codename(), e);
+                            schedule.finish(job, State::Failed);
+                            continue;
+                        }
+                    };
+
+                    misses += 1;
+                    running += 1;
+                    let prefix = format!("{}/{}", package.name, release.codename());
+                    let mut args = vec![
+                        "build-one".to_string(),
+                        package.name.to_string(),
+                        release.codename().to_string(),
+                        key.to_string(),
+                    ];
+                    args.extend(forward_options(matches, &options));
+                    let send = send.clone();
+                    thread::spawn(move || {
+                        let result = worker(&prefix, &args);
+                        if let Err(ref e) = result {
+                            error!("{}: {:?}", prefix, e);
+                        }
+                        send.send((job, key, result.is_ok()))
+                            .expect("main thread waits for workers");
+                    });
+                }
+
+                if 0 == running {
+                    break;
+                }
+
+                let (job, key, ok) = recv.recv()?;
+                running -= 1;
+                match cache::lookup(cache, &key) {
+                    Ok(Some(debs)) if ok => {
+                        built.insert(job, debs);
+                        schedule.finish(job, publish(schedule.release(job), State::Built));
+                    }
+                    Ok(_) => schedule.finish(job, State::Failed),
+                    Err(e) => {
+                        let (package, release) = (schedule.package(job), schedule.release(job));
+                        error!("{} on {}: {:?}", package.name, release.codename(), e);
+                        schedule.finish(job, State::Failed);
+                    }
+                }
+            }
+
+            print!("{}", schedule.summary());
+            info!("cache: {} hits, {} misses", hits, misses);
+            let failures = schedule.failures();
+            ensure!(0 == failures, "{} builds failed", failures);
+        }
+        ("build-one", Some(matches)) => {
+            let name = matches.value_of("package").unwrap();
+            let codename = matches.value_of("release").unwrap();
+            let key = matches.value_of("key").unwrap();
+            let (package, release) = find(name, codename)?;
+
+            let debs = build::build(dirs.cache_dir(), release, &package, &options(matches)?)?;
+            cache::store(dirs.cache_dir(), key, &debs)?;
+        }
+        ("verify-repro", Some(matches)) => {
+            let name = matches.value_of("package").unwrap();
+            let codename = matches.value_of("release").unwrap();
+            let (package, release) = find(name, codename)?;
+
+            // each run writes somewhere of its own, leaving the published debs alone
+            let scratch = tempfile::TempDir::new()?;
+            let mut runs = Vec::new();
+            for run in &["first", "second"] {
+                info!("{} on {}: {} build", name, codename, run);
+                let options = build::Options {
+                    reproducible: true,
+                    out: Some(scratch.path().join(run)),
+                    ..options(matches)?
+                };
+                runs.push(build::build(dirs.cache_dir(), release, &package, &options)?);
+            }
+
+            let differences = repro::compare_runs(&runs[0], &runs[1])?;
+            for difference in &differences {
+                println!("{}", difference);
             }
+            ensure!(
+                differences.is_empty(),
+                "{} on {} is not reproducible: {} differences",
+                name,
+                codename,
+                differences.len()
+            );
+            println!("{} on {} is reproducible", name, codename);
         }
         ("namespace", Some(matches)) => {
             let root = matches.is_present("root");
             let cmd = matches.value_of("cmd").unwrap().as_bytes();
+            let defaults = Config::default();
+            let config = Config {
+                dev: !matches.is_present("no-dev"),
+                tmpfs: match matches.values_of("tmpfs") {
+                    Some(paths) => paths.map(PathBuf::from).collect(),
+                    None => defaults.tmpfs.clone(),
+                },
+                binds: matches
+                    .values_of("bind")
+                    .into_iter()
+                    .flatten()
+                    .map(|bind| bind.parse())
+                    .collect::<Result<_, Error>>()?,
+                network: match matches.is_present("host-network") {
+                    true => Network::Host,
+                    false => Network::Loopback,
+                },
+                ..defaults
+            };
 
             info!("unpacking...");
             let child = namespace::unpack_to_temp(dirs.cache_dir(), "disco")
                 .with_context(|| anyhow!("opening distro container"))?;
             info!("unpacked!");
 
-            let mut child =
-                namespace::launch_our_init(&child).with_context(|| anyhow!("launching init"))?;
+            let mut child = namespace::launch_our_init(&child, &config)
+                .with_context(|| anyhow!("launching init"))?;
 
-            namespace::child::await_ready(&mut child)?;
-            namespace::child::execute(&mut child, root, cmd)?;
-            namespace::child::shutdown(&mut child)?;
+            let sink = &mut namespace::child::Stdout;
+            namespace::child::await_ready(&mut child, sink)?;
+            let code = namespace::child::execute(&mut child, root, cmd, sink)?;
+            namespace::child::shutdown(&mut child, sink)?;
+            ensure!(0 == code, "command exited with {}", code);
         }
         ("fetch", _) => {
             let ubuntu_codenames = RELEASES
@@ -77,8 +378,215 @@ fn main() -> Result<(), Error> {
 
             fetch_images::fetch_ubuntu(dirs.cache_dir(), &ubuntu_codenames)?;
         }
+        ("logs", Some(matches)) => {
+            let package = matches.value_of("package").unwrap();
+            let release = matches.value_of("release").unwrap();
+            let (meta, raw) =
+                logs::open(dirs.cache_dir(), package, release, matches.value_of("run"))?;
+
+            let time = |secs: u64| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs));
+            println!("{} on {}, run {}", meta.package, meta.release, meta.run);
+            println!("started:  {}", time(meta.start));
+            match meta.end {
+                Some(end) => println!("finished: {}", time(end)),
+                None => println!("finished: never (still running, or killed)"),
+            }
+            for phase in &meta.phases {
+                println!(
+                    "  {:<9} {}",
+                    phase.name,
+                    if phase.ok { "ok" } else { "FAILED" }
+                );
+                for command in &phase.commands {
+                    println!("    {:>3} {}", command.exit_code, command.command);
+                }
+            }
+            for msg in &meta.debug {
+                println!("debug: {}", msg);
+            }
+            if let Some(usage) = &meta.usage {
+                if let Some(peak) = usage.peak_memory {
+                    println!("peak memory: {} MiB", peak >> 20);
+                }
+                if let Some(usec) = usage.cpu_usec {
+                    println!("cpu time: {:.1}s", usec as f64 / 1e6);
+                }
+            }
+            if let Some(error) = &meta.error {
+                println!("error: {}", error);
+            }
+            println!();
+
+            io::copy(&mut fs::File::open(&raw)?, &mut io::stdout())?;
+        }
         _ => unreachable!(),
     }
 
     Ok(())
 }
+
+const LIMITS: [&str; 4] = ["memory", "cpus", "pids", "io-weight"];
+
+fn option_args() -> Vec<clap::Arg<'static, 'static>> {
+    use clap::Arg;
+    vec![
+        Arg::with_name("reproducible")
+            .long("reproducible")
+            .help("set SOURCE_DATE_EPOCH, and clamp packaged mtimes to it"),
+        Arg::with_name("memory")
+            .long("memory")
+            .takes_value(true)
+            .help("memory limit for each build, e.g. 4G"),
+        Arg::with_name("cpus")
+            .long("cpus")
+            .takes_value(true)
+            .help("cpu limit for each build, e.g. 1.5"),
+        Arg::with_name("pids")
+            .long("pids")
+            .takes_value(true)
+            .help("process and thread limit for each build"),
+        Arg::with_name("io-weight")
+            .long("io-weight")
+            .takes_value(true)
+            .help("io weight for each build, 1-10000"),
+        Arg::with_name("cgroup")
+            .long("cgroup")
+            .takes_value(true)
+            .help("delegated cgroup v2 directory to put limited builds under"),
+    ]
+}
+
+fn options(matches: &clap::ArgMatches) -> Result<build::Options, Error> {
+    let limits = Limits {
+        memory: matches
+            .value_of("memory")
+            .map(cgroup::parse_size)
+            .transpose()?,
+        cpus: matches.value_of("cpus").map(|v| v.parse()).transpose()?,
+        pids: matches.value_of("pids").map(|v| v.parse()).transpose()?,
+        io_weight: matches
+            .value_of("io-weight")
+            .map(|v| v.parse())
+            .transpose()?,
+        parent: matches.value_of("cgroup").map(PathBuf::from),
+    };
+    Ok(build::Options {
+        limits,
+        reproducible: matches.is_present("reproducible"),
+        out: None,
+    })
+}
+
+/// The option arguments, as they were passed to us, to pass on to a worker, along with
+/// the cgroup parent we've already prepared for it.
+fn forward_options(matches: &clap::ArgMatches, options: &build::Options) -> Vec<String> {
+    let mut ret = Vec::new();
+    if matches.is_present("reproducible") {
+        ret.push("--reproducible".to_string());
+    }
+    for name in &LIMITS {
+        if let Some(value) = matches.value_of(name) {
+            ret.push(format!("--{}", name));
+            ret.push(value.to_string());
+        }
+    }
+    if let Some(parent) = &options.limits.parent {
+        ret.push("--cgroup".to_string());
+        ret.push(parent.to_string_lossy().to_string());
+    }
+    ret
+}
+
+/// Make sure the cache has every git commit and download `package`'s sources need.
+fn fetch_sources(cache: &Path, package: &specs::Package) -> Result<(), Error> {
+    for command in &package.source {
+        match command {
+            specs::Command::Clone { repo, sha, .. } => {
+                git::check_cloned(format!("{}?rev={}", repo, sha))?;
+            }
+            specs::Command::Fetch { url, sha256, .. } => {
+                sources::fetch(cache, url, sha256)?;
+            }
+            _ => continue,
+        };
+    }
+    Ok(())
+}
+
+/// The hash of `release`'s base image, remembered in `hashed`, as the images are large.
+fn image_sha256(
+    cache: &Path,
+    release: Release,
+    hashed: &mut HashMap<&'static str, String>,
+) -> Result<String, Error> {
+    if let Some(sha256) = hashed.get(release.codename()) {
+        return Ok(sha256.to_string());
+    }
+    let image = fetch_images::base_image(cache, release.codename())?.join("root.tar.zstd");
+    let sha256 =
+        sources::sha256_file(&image).with_context(|| anyhow!("hashing base image {:?}", image))?;
+    hashed.insert(release.codename(), sha256.to_string());
+    Ok(sha256)
+}
+
+fn find(name: &str, codename: &str) -> Result<(specs::Package, &'static Release), Error> {
+    let package = specs::load_from("specs")?
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| anyhow!("no such package: {:?}", name))?;
+    let release = RELEASES
+        .iter()
+        .find(|r| r.codename() == codename)
+        .ok_or_else(|| anyhow!("no such release: {:?}", codename))?;
+    Ok((package, release))
+}
+
+/// Run a build in a fresh process, so its sandbox isn't forked from a threaded parent,
+/// printing its output with `prefix` on every line.
+fn worker(prefix: &str, args: &[String]) -> Result<(), Error> {
+    let mut child = process::Command::new(env::current_exe()?)
+        .args(args)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .with_context(|| anyhow!("starting worker"))?;
+
+    let stdout = child.stdout.take().expect("piped");
+    let stderr = child.stderr.take().expect("piped");
+    let printers = vec![
+        print_prefixed(prefix.to_string(), stdout),
+        print_prefixed(prefix.to_string(), stderr),
+    ];
+
+    let status = child.wait()?;
+    for printer in printers {
+        printer.join().expect("printing doesn't panic")?;
+    }
+
+    ensure!(status.success(), "worker failed: {}", status);
+    Ok(())
+}
+
+fn print_prefixed<R: io::Read + Send + 'static>(
+    prefix: String,
+    from: R,
+) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut from = io::BufReader::new(from);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if 0 == from.read_until(b'\n', &mut line)? {
+                return Ok(());
+            }
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            write!(stdout, "{}: ", prefix)?;
+            stdout.write_all(&line)?;
+            if !line.ends_with(b"\n") {
+                writeln!(stdout)?;
+            }
+        }
+    })
+}
@@ -16,7 +16,7 @@ use anyhow::Error;
 use anyhow::Context;
 use nix::unistd;
 
-use fappa::namespace::child::{CodeFrom, CodeTo, Proto};
+use fappa::namespace::child::{CodeFrom, CodeTo, Proto, HANDSHAKE};
 
 fn main() -> Result<(), Error> {
     assert_eq!(
@@ -62,13 +62,15 @@ fn work(host: &mut Host) -> Result<(), Error> {
         host.println(format!("{} {:?}", p.pid(), p.cmdline()?))?;
     }
 
-    host.proto.write_msg(CodeFrom::Ready, &[])?;
+    host.proto
+        .write_msg(CodeFrom::Ready, HANDSHAKE.as_bytes())?;
 
     loop {
         let (code, data) = host.proto.read_msg()?;
         match code {
             CodeTo::RunWithoutRoot => run(host, data, false)?,
             CodeTo::RunAsRoot => run(host, data, true)?,
+            CodeTo::IsolateNetwork => isolate_network(host)?,
             CodeTo::Die => return Ok(()),
             _ => bail!("unsupported code: {:?}", code),
         };
@@ -119,6 +121,16 @@ fn run(host: &mut Host, data: Vec<u8>, root: bool) -> Result<(), Error> {
     Ok(())
 }
 
+/// Everything we start from now on can only see its own `lo`.
+fn isolate_network(host: &mut Host) -> Result<(), Error> {
+    use nix::sched::*;
+    unshare(CloneFlags::CLONE_NEWNET).with_context(|| anyhow!("unshare network"))?;
+    fappa::namespace::net::loopback_up()?;
+
+    host.proto.write_msg(CodeFrom::NetworkIsolated, &[])?;
+    Ok(())
+}
+
 fn drive_child(host: &mut Host, proc: &mut process::Child, data: &[u8]) -> Result<(), Error> {
     {
         proc.stdin
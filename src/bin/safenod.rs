@@ -9,6 +9,8 @@ use anyhow::bail;
 use anyhow::Error;
 use nix::sys::stat;
 
+use fappa::namespace::dev::DEVICES;
+
 fn main() -> Result<(), Error> {
     let mut args = env::args_os();
 
@@ -28,26 +30,13 @@ fn main() -> Result<(), Error> {
 
     let all_read_write = stat::Mode::from_bits(0o666).expect("static data");
 
-    for (name, major, minor) in &[
-        ("null", 1, 3),
-        ("zero", 1, 5),
-        ("full", 1, 7),
-        (
-            "random",
-            1,
-            #[cfg(feature = "real-random")]
-            8,
-            #[cfg(not(feature = "real-random"))]
-            9,
-        ),
-        ("urandom", 1, 9),
-    ] {
-        path.push(name);
+    for device in &DEVICES {
+        path.push(device.name);
         stat::mknod(
             &path,
             stat::SFlag::S_IFCHR,
             all_read_write,
-            stat::makedev(*major, *minor),
+            stat::makedev(device.major, device.minor),
         )?;
         path.pop();
     }
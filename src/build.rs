@@ -1,126 +1,531 @@
 use std::fs;
-use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::ensure;
+use anyhow::Context;
 use anyhow::Error;
 use fs_extra::dir;
-use tempfile;
+use log::info;
+use log::warn;
+use tempfile_fast::Sponge;
 
+use crate::deb;
+use crate::logs::BuildLog;
+use crate::namespace;
+use crate::namespace::cgroup::Limits;
+use crate::namespace::child;
+use crate::namespace::child::Child;
+use crate::namespace::config::Bind;
+use crate::namespace::config::Config;
+use crate::namespace::config::Network;
+use crate::patch;
+use crate::repo;
+use crate::shlibs;
+use crate::snapshot;
+use crate::snapshot::Snapshot;
+use crate::snapshot::Type;
+use crate::sources;
+use crate::spec_sh::quote;
 use crate::specs::Command;
+use crate::specs::Output;
+use crate::specs::Phase;
 use crate::specs::Package;
 use crate::Release;
 
-enum Kind {
-    Modified,
-    Added,
-    Deleted,
+/// Where the packages built for the same release are mounted inside the sandbox.
+const LOCAL_REPO: &str = "/var/lib/fappa/repo";
+
+/// How to build, as opposed to what to build.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Resource limits for the sandbox; what it used is recorded in the log.
+    pub limits: Limits,
+    /// Set `SOURCE_DATE_EPOCH`, and clamp the packaged mtimes to it.
+    pub reproducible: bool,
+    /// Where to write the `.deb`s, instead of the release's repository, in `debs_dir`.
+    pub out: Option<PathBuf>,
+}
+
+/// Build `package` for `release` in a fresh sandbox, reporting each phase as it finishes.
+///
+/// The output, and a record of the phases, are kept in the cache; see [`crate::logs`].
+/// Returns the `.deb`s written, one per output.
+pub fn build<P: AsRef<Path>>(
+    cache: P,
+    release: &Release,
+    package: &Package,
+    options: &Options,
+) -> Result<Vec<PathBuf>, Error> {
+    let cache = cache.as_ref();
+    let mut log = BuildLog::create(cache, &package.name, release.codename())?;
+    let result = build_logged(cache, release, package, options, &mut log);
+    let dir = log.finish(&result)?;
+    info!(
+        "{} on {}: log in {:?}",
+        package.name,
+        release.codename(),
+        dir
+    );
+    result
 }
 
-impl From<u64> for Kind {
-    fn from(val: u64) -> Self {
-        match val {
-            0 => Kind::Modified,
-            1 => Kind::Added,
-            2 => Kind::Deleted,
-            other => panic!("invalid change kind: {}", other),
+fn build_logged(
+    cache: &Path,
+    release: &Release,
+    package: &Package,
+    options: &Options,
+    log: &mut BuildLog,
+) -> Result<Vec<PathBuf>, Error> {
+    let root = namespace::unpack_to_temp(cache, release.codename())
+        .with_context(|| anyhow!("unpacking {} image", release.codename()))?;
+
+    log.start_phase("source")?;
+    let build_dir = root.path().join("build");
+    let dated = extract_sources(cache, package, &build_dir)
+        .with_context(|| anyhow!("source phase failed"))?;
+    log.end_phase()?;
+
+    let epoch = if options.reproducible {
+        let epoch = match dated {
+            Some(epoch) => epoch,
+            None => {
+                warn!(
+                    "{} has no sources to date; using 0 as SOURCE_DATE_EPOCH",
+                    package.name
+                );
+                0
+            }
+        };
+        info!(
+            "{} on {}: SOURCE_DATE_EPOCH={}",
+            package.name,
+            release.codename(),
+            epoch
+        );
+        Some(epoch)
+    } else {
+        None
+    };
+
+    let repo = local_repo(cache, release, root.path())?;
+    let config = Config {
+        limits: options.limits.clone(),
+        binds: vec![Bind::read_only(repo, LOCAL_REPO)],
+        // just for build_dep's apt-get; see `run_phases`
+        network: Network::Host,
+        ..Config::default()
+    };
+    let mut child =
+        namespace::launch_our_init(&root, &config).with_context(|| anyhow!("launching init"))?;
+    child::await_ready(&mut child, log)?;
+    let result = run_phases(&mut child, root.path(), package, epoch, log);
+    child::shutdown(&mut child, log)?;
+    if let Some(usage) = child.usage()? {
+        info!(
+            "{} on {}: used {:?}",
+            package.name,
+            release.codename(),
+            usage
+        );
+        log.usage(usage)?;
+    }
+    child.wait()?;
+    let (before, after) = result?;
+    let changes = snapshot::diff(&before, &after);
+
+    let excluded = |path: &String| package.exclude_files.is_match(path);
+
+    let deleted: Vec<&String> = changes.deleted.iter().filter(|p| !excluded(p)).collect();
+    ensure!(
+        deleted.is_empty(),
+        "some files were removed, and they are not excluded: {:?}",
+        deleted
+    );
+
+    let modified: Vec<&String> = changes.modified.iter().filter(|p| !excluded(p)).collect();
+    ensure!(
+        modified.is_empty(),
+        "some existing files were modified, and they are not excluded: {:?}",
+        modified
+    );
+
+    if !changes.dir_metadata.is_empty() {
+        info!(
+            "ignoring changed directory metadata: {:?}",
+            changes.dir_metadata
+        );
+    }
+
+    // new directories are created as needed, it's only their contents which are packaged
+    let new: Vec<String> = changes
+        .added
+        .into_iter()
+        .filter(|path| !excluded(path))
+        .filter(|path| Type::Dir != after.entries[path].kind)
+        .collect();
+
+    let dest = match &options.out {
+        Some(out) => out.to_path_buf(),
+        None => debs_dir(cache, release),
+    };
+    fs::create_dir_all(&dest)?;
+
+    let version = format!("{}~{}", package.version, release.codename());
+    let partitioned = partition(&new, &package.outputs)?;
+
+    // libraries in our own outputs are depended on exactly, as they're built together
+    let mut resolver = shlibs::Resolver::load(root.path())?;
+    for (output, files) in package.outputs.iter().zip(&partitioned) {
+        for file in files {
+            let name = file.rsplit('/').next().expect("split is never empty");
+            resolver.add_local(name, format!("{} (= {})", output.name, version));
         }
     }
+
+    let mut written = Vec::new();
+    for (output, files) in package.outputs.iter().zip(&partitioned) {
+        let provided =
+            |soname: &&String| files.iter().any(|f| f.ends_with(&format!("/{}", soname)));
+        let needed = shlibs::needed_by(root.path(), files)?;
+        let automatic = shlibs::resolve_all(&resolver, needed.iter().filter(|s| !provided(s)));
+
+        let control = deb::Control {
+            package: output.name.to_string(),
+            version: version.to_string(),
+            depends: shlibs::merge(&output.dep, automatic),
+            description: format!("{}, built from {} by fappa", output.name, package.name),
+        };
+        let path = dest.join(control.file_name());
+        let mut sponge = Sponge::new_for(&path)?;
+        let clamp = epoch.map(|epoch| epoch.max(0) as u64);
+        deb::write(&mut sponge, &control, root.path(), files, clamp)
+            .with_context(|| anyhow!("writing {:?}", path))?;
+        sponge.commit()?;
+        info!(
+            "{} on {}: wrote {:?}",
+            package.name,
+            release.codename(),
+            path
+        );
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/// Where the packages built for `release` are written.
+pub fn debs_dir(cache: &Path, release: &Release) -> PathBuf {
+    cache.join("debs").join(release.codename())
+}
+
+/// Make the packages already built for `release` available to apt inside `root`,
+/// preferring them over the distro's, so local packages can be build dependencies.
+///
+/// Returns the repository to mount.
+fn local_repo(cache: &Path, release: &Release, root: &Path) -> Result<PathBuf, Error> {
+    let dir = debs_dir(cache, release);
+    fs::create_dir_all(&dir)?;
+    if !dir.join("Packages").is_file() {
+        repo::update(&dir, release.codename(), None)?;
+    }
+
+    let apt = root.join("etc/apt");
+    fs::create_dir_all(apt.join("sources.list.d"))?;
+    fs::write(
+        apt.join("sources.list.d/fappa.list"),
+        format!("deb [trusted=yes] file:{} ./\n", LOCAL_REPO),
+    )?;
+    fs::create_dir_all(apt.join("preferences.d"))?;
+    fs::write(
+        apt.join("preferences.d/fappa.pref"),
+        "Package: *\nPin: release o=fappa\nPin-Priority: 1001\n",
+    )?;
+
+    Ok(dir)
 }
 
-pub fn build(release: &Release, package: &Package) -> Result<(), Error> {
-    let dir = tempfile::TempDir::new()?;
-    {
-        let mut dockerfile = dir.path().to_path_buf();
-        dockerfile.push("Dockerfile");
-        let mut dockerfile = fs::File::create(dockerfile)?;
-
-        writeln!(dockerfile, "FROM fappa-{}", release.codename())?;
-        writeln!(dockerfile, "WORKDIR /build")?;
-
-        for command in &package.ser.commands {
-            match command {
-                // Command::WorkDir(dir) => writeln!(dockerfile, "WORKDIR {}", dir)?,
-                Command::Autoreconf => writeln!(
-                    dockerfile,
-                    "RUN autoreconf -fvi && ./configure --prefix=/usr/local && make -j 2"
-                )?,
-                Command::CMake => writeln!(
-                    dockerfile,
-                    "RUN mkdir cmake-build-package && cd cmake-build-package && cmake .. && make -j 2"
-                )?,
-                _ => unimplemented!("build: {:?}", command),
-            }
+/// Split the generated `files` between the `outputs`, by their `include_files`.
+///
+/// Every file must belong to exactly one output.
+pub fn partition<'f>(files: &'f [String], outputs: &[Output]) -> Result<Vec<Vec<&'f str>>, Error> {
+    let mut ret = vec![Vec::new(); outputs.len()];
+    let mut unclaimed = Vec::new();
+
+    for file in files {
+        let claimed: Vec<usize> = outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| output.include_files.is_match(file))
+            .map(|(i, _)| i)
+            .collect();
+
+        match claimed.as_slice() {
+            [] => unclaimed.push(file.as_str()),
+            [i] => ret[*i].push(file.as_str()),
+            many => bail!(
+                "{:?} is included by more than one output: {}",
+                file,
+                many.iter()
+                    .map(|&i| outputs[i].name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
         }
     }
 
-    unimplemented!(
-        r"crate::dump_lines(
-        *release,
-        &docker.images().build(
-            &BuildOptions::builder(crate::tempdir_as_bad_str(&dir)?)
-                .build(),
-        )?,
-    )?"
+    ensure!(
+        unclaimed.is_empty(),
+        "some files were generated but not included: {:?}",
+        unclaimed
     );
 
-    let mut new: Vec<String> = Vec::new();
-    let mut rm: Vec<String> = Vec::new();
+    Ok(ret)
+}
 
-    // TODO: Hmm, no. Modified and Added are not the same. We need to ensure that only
-    // TODO: directories are modified, and that they're in ignorable attributes?
+/// Put the package's sources in place, from the host, as they're all fetched and verified here.
+///
+/// Returns the date of the newest of the sources, if there were any: commits by their date,
+/// and everything else by the newest file in it, as it was *before* being extracted, so
+/// it's the same for every build.
+fn extract_sources(cache: &Path, package: &Package, build: &Path) -> Result<Option<i64>, Error> {
+    fs::create_dir_all(build)?;
+    let mut dated = None;
 
-    // should really be a list of everything that's in the tagged image?
-    let existing_files = ["/usr", "/usr/local", "/build"];
+    for command in &package.source {
+        let date = match command {
+            Command::Clone {
+                repo, sha, dest, ..
+            } => Some(clone(repo, sha, &inside(build, dest)?)?),
+            Command::Fetch { url, sha256, dest } => {
+                let archive = sources::fetch(cache, url, sha256)?;
+                sources::unpack_archive(&archive, inside(build, dest)?)?;
+                sources::archive_mtime(&archive)?
+            }
+            Command::Local { path, dest } => {
+                let local = sources::local(&package.dir, path)?;
+                sources::copy_local(&local, inside(build, dest)?)?;
+                newest_mtime(&local)?
+            }
+            Command::Patches { dir, dest } => {
+                let dir = package.dir.join(dir);
+                patch::apply_series(&dir, inside(build, dest)?)?;
+                newest_mtime(&dir)?
+            }
+            other => bail!("only sources can be used in `source`: {:?}", other),
+        };
+        dated = dated.max(date);
+    }
+
+    Ok(dated)
+}
 
-    unimplemented!("rm.retain(|path| !matches(path, &package.exclude_files()));");
+#[cfg(feature = "git2")]
+fn clone(repo: &str, sha: &str, dest: &Path) -> Result<i64, Error> {
+    crate::git::export(repo, sha, dest)
+}
+
+#[cfg(not(feature = "git2"))]
+fn clone(repo: &str, _sha: &str, _dest: &Path) -> Result<i64, Error> {
+    bail!("can't clone {:?}: built without git support", repo)
+}
 
+/// The newest modification time of the files in `dir`, in seconds.
+///
+/// Directories are skipped: their times change whenever anything is added to them.
+fn newest_mtime(dir: &Path) -> Result<Option<i64>, Error> {
+    let mut newest = None;
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            newest = newest.max(Some(entry.metadata()?.mtime()));
+        }
+    }
+    Ok(newest)
+}
+
+/// `dest`, which must stay inside `build`.
+fn inside(build: &Path, dest: &str) -> Result<PathBuf, Error> {
+    let dest = Path::new(dest);
     ensure!(
-        rm.is_empty(),
-        "some files were removed, and they are not excluded: {:?}",
-        rm
+        dest.components().all(|c| match c {
+            Component::Normal(_) | Component::CurDir => true,
+            _ => false,
+        }),
+        "source destinations must be relative, and inside /build: {:?}",
+        dest
     );
+    Ok(build.join(dest))
+}
 
-    new.retain(|path| {
-        !existing_files.contains(&path.as_str())
-            && !matches(path, unimplemented!("&package.exclude_files()"))
-    });
-    {
-        let violations: Vec<&String> = new
-            .iter()
-            .filter(|path| !matches(path, unimplemented!("&package.include_files")))
-            .collect();
-        ensure!(
-            violations.is_empty(),
-            "some files were generated by not included: {:?}",
-            violations
-        );
+/// Run everything inside the sandbox, snapshotting the root before and after the install phase.
+///
+/// Only build_dep can use the network; the sandbox is cut off before the build starts.
+fn run_phases(
+    child: &mut Child,
+    root: &Path,
+    package: &Package,
+    epoch: Option<i64>,
+    log: &mut BuildLog,
+) -> Result<(Snapshot, Snapshot), Error> {
+    let mut deps = "chown -R 212:212 /build /usr/local".to_string();
+    if !package.build_dep.is_empty() {
+        deps.push_str(&format!(
+            " && apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends {}",
+            quoted(&package.build_dep)
+        ));
+    }
+    log.start_phase("build_dep")?;
+    run(child, true, &deps, log).with_context(|| anyhow!("build_dep phase failed"))?;
+    log.end_phase()?;
+    child::isolate_network(child, log)?;
+
+    let mut shell = Shell::new();
+    if let Some(epoch) = epoch {
+        shell.step(
+            Phase::Build,
+            &Command::Env {
+                key: "SOURCE_DATE_EPOCH".to_string(),
+                value: epoch.to_string(),
+            },
+        )?;
+    }
+    log.start_phase("build")?;
+    // each build system installs from where it built, before any of the `install` steps
+    let mut installs = Vec::new();
+    for command in &package.build {
+        if let Some(line) = shell.step(Phase::Build, command)? {
+            run(child, false, &line, log).with_context(|| anyhow!("build phase failed"))?;
+        }
+        if command.is_build_system() {
+            installs.extend(shell.step(Phase::Install, command)?);
+        }
     }
+    log.end_phase()?;
 
-    for line in new {
-        println!("{}", line);
+    log.start_phase("install")?;
+    let before = snapshot::take(root)?;
+    for line in &installs {
+        run(child, false, line, log).with_context(|| anyhow!("install phase failed"))?;
+    }
+    for command in &package.install {
+        if let Some(line) = shell.step(Phase::Install, command)? {
+            run(child, false, &line, log).with_context(|| anyhow!("install phase failed"))?;
+        }
     }
+    let after = snapshot::retake(root, &before)?;
+    log.end_phase()?;
 
+    Ok((before, after))
+}
+
+fn run(child: &mut Child, root: bool, line: &str, log: &mut BuildLog) -> Result<(), Error> {
+    let code = child::execute(child, root, line.as_bytes(), log)?;
+    log.command(line, code)?;
+    ensure!(0 == code, "`{}` exited with {}", line, code);
     Ok(())
 }
 
-fn matches(path: &str, patterns: &[String]) -> bool {
-    for pattern in patterns {
-        if pattern.ends_with("/**") {
-            let pattern = pattern.trim_end_matches('*');
-            assert!(
-                !pattern.contains(|c: char| c == '*' || '?' == c),
-                "unsupported pattern extra wildcards: {}",
-                pattern
-            );
-            if path.starts_with(pattern) {
-                return true;
+/// The state carried between steps: each step runs in a fresh shell.
+pub struct Shell {
+    workdir: String,
+    env: Vec<(String, String)>,
+}
+
+impl Shell {
+    pub fn new() -> Shell {
+        Shell {
+            workdir: "/build".to_string(),
+            env: Vec::new(),
+        }
+    }
+
+    /// The line of shell to run for this step, if it needs running at all.
+    ///
+    /// Build systems do their configure-and-build in the build phase,
+    /// and their install in the install phase; only `build` lists them, see `run_phases`.
+    pub fn step(&mut self, phase: Phase, command: &Command) -> Result<Option<String>, Error> {
+        let script = match (phase, command) {
+            (_, Command::WorkDir(dir)) => {
+                self.workdir = Path::new(&self.workdir)
+                    .join(dir)
+                    .to_str()
+                    .expect("joining strs")
+                    .to_string();
+                return Ok(None);
+            }
+            (_, Command::Env { key, value }) => {
+                self.env.retain(|(k, _)| k != key);
+                self.env.push((key.to_string(), value.to_string()));
+                return Ok(None);
+            }
+            (_, Command::Run(line)) => line.to_string(),
+            (_, Command::Clone { .. })
+            | (_, Command::Fetch { .. })
+            | (_, Command::Local { .. })
+            | (_, Command::Patches { .. }) => {
+                bail!("sources aren't built in the shell: {:?}", command)
+            }
+            (Phase::Source, _) => bail!("build systems can't run as a source: {:?}", command),
+
+            (Phase::Build, Command::Autoreconf) => {
+                "autoreconf -fvi && ./configure --prefix=/usr/local && make -j 2".to_string()
+            }
+            (Phase::Install, Command::Autoreconf) => "make install".to_string(),
+
+            (Phase::Build, Command::CMake) => {
+                "mkdir cmake-build-package && cd cmake-build-package && cmake .. && make -j 2"
+                    .to_string()
+            }
+            (Phase::Install, Command::CMake) => "make -C cmake-build-package install".to_string(),
+
+            (Phase::Build, Command::Meson { args }) => format!(
+                "meson --prefix=/usr/local {} meson-build-package && ninja -C meson-build-package -j 2",
+                quoted(args)
+            ),
+            (Phase::Install, Command::Meson { .. }) => {
+                "ninja -C meson-build-package install".to_string()
+            }
+
+            (Phase::Build, Command::Make { args }) => format!("make -j 2 {}", quoted(args)),
+            (Phase::Install, Command::Make { args }) => {
+                format!("make install {}", quoted(args))
+            }
+
+            (Phase::Build, Command::Cargo { args }) => {
+                format!("cargo build --release {}", quoted(args))
+            }
+            (Phase::Install, Command::Cargo { args }) => format!(
+                "cargo install --path . --root /usr/local {}",
+                quoted(args)
+            ),
+
+            (Phase::Build, Command::SetupPy { args }) => {
+                format!("python3 setup.py build {}", quoted(args))
+            }
+            (Phase::Install, Command::SetupPy { .. }) => {
+                "python3 setup.py install --prefix=/usr/local --skip-build".to_string()
             }
-        } else {
-            unimplemented!("unsupported pattern type: {}", pattern)
+        };
+
+        let mut line = format!("cd {}", quote(&self.workdir));
+        for (key, value) in &self.env {
+            line.push_str(&format!(" && export {}={}", key, quote(value)));
         }
+        line.push_str(&format!(" && ( {} )", script));
+        Ok(Some(line))
     }
+}
 
-    false
+fn quoted(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 fn sorted_spaced<S: AsRef<str>, T: IntoIterator<Item = S>>(list: T) -> String {
@@ -128,3 +533,182 @@ fn sorted_spaced<S: AsRef<str>, T: IntoIterator<Item = S>>(list: T) -> String {
     vec.sort();
     vec.join(" ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Shell;
+    use crate::patterns::Patterns;
+    use crate::specs::Command;
+    use crate::specs::Output;
+    use crate::specs::Package;
+    use crate::specs::Phase;
+
+    #[test]
+    fn shell_state() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            None,
+            shell
+                .step(Phase::Build, &Command::WorkDir("foo".to_string()))
+                .unwrap()
+        );
+        let env = Command::Env {
+            key: "CFLAGS".to_string(),
+            value: "-O2 -g".to_string(),
+        };
+        assert_eq!(None, shell.step(Phase::Build, &env).unwrap());
+        assert_eq!(
+            Some("cd /build/foo && export CFLAGS='-O2 -g' && ( make )".to_string()),
+            shell
+                .step(Phase::Build, &Command::Run("make".to_string()))
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            shell
+                .step(Phase::Build, &Command::WorkDir("/src".to_string()))
+                .unwrap()
+        );
+        assert_eq!(
+            Some("cd /src && export CFLAGS='-O2 -g' && ( make install )".to_string()),
+            shell
+                .step(Phase::Install, &Command::Run("make install".to_string()))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn build_systems() {
+        let mut shell = Shell::new();
+        let meson = Command::Meson {
+            args: vec!["-Dfoo=bar baz".to_string()],
+        };
+        assert_eq!(
+            Some(
+                "cd /build && ( meson --prefix=/usr/local '-Dfoo=bar baz' meson-build-package && ninja -C meson-build-package -j 2 )"
+                    .to_string()
+            ),
+            shell.step(Phase::Build, &meson).unwrap()
+        );
+        assert_eq!(
+            Some("cd /build && ( ninja -C meson-build-package install )".to_string()),
+            shell.step(Phase::Install, &meson).unwrap()
+        );
+        assert!(shell.step(Phase::Source, &meson).is_err());
+    }
+
+    #[test]
+    fn partition() {
+        let output = |name: &str, patterns: &[&str]| {
+            let mut output = Output::new(name);
+            output.include_files =
+                Patterns::new(patterns.iter().map(|s| s.to_string()).collect()).unwrap();
+            output
+        };
+        let outputs = vec![
+            output("libfoo1", &["/usr/local/lib/*.so.*"]),
+            output(
+                "libfoo-dev",
+                &["/usr/local/include/**", "/usr/local/lib/*.so"],
+            ),
+        ];
+        let files: Vec<String> = vec![
+            "/usr/local/lib/libfoo.so.1",
+            "/usr/local/lib/libfoo.so",
+            "/usr/local/include/foo.h",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        assert_eq!(
+            vec![
+                vec!["/usr/local/lib/libfoo.so.1"],
+                vec!["/usr/local/lib/libfoo.so", "/usr/local/include/foo.h"],
+            ],
+            super::partition(&files, &outputs).unwrap()
+        );
+
+        let unclaimed = vec!["/usr/local/share/doc/foo".to_string()];
+        assert!(super::partition(&unclaimed, &outputs).is_err());
+
+        let greedy = vec![outputs[0].clone(), output("everything", &["/usr/local/**"])];
+        let err = super::partition(&files, &greedy).unwrap_err().to_string();
+        assert!(err.contains("libfoo1, everything"), "{}", err);
+    }
+
+    #[test]
+    fn source_date_without_git() {
+        use nix::sys::stat::utimes;
+        use nix::sys::time::TimeVal;
+        use nix::sys::time::TimeValLike;
+        use sha2::Digest;
+        use std::fs;
+
+        let spec = tempfile::TempDir::new().unwrap();
+        let cache = tempfile::TempDir::new().unwrap();
+
+        // an upstream tarball, already downloaded
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o644);
+        header.set_mtime(1_500_000_000);
+        header.set_cksum();
+        tar.append_data(&mut header, "foo-1.0/hello", &b"hello\n"[..])
+            .unwrap();
+        let archive = tar.into_inner().unwrap();
+        let sha256 = format!("{:x}", sha2::Sha256::digest(&archive));
+        let downloaded = cache.path().join("sources").join(&sha256);
+        fs::create_dir_all(&downloaded).unwrap();
+        fs::write(downloaded.join("foo-1.0.tar"), &archive).unwrap();
+
+        fs::create_dir(spec.path().join("debian")).unwrap();
+        fs::write(spec.path().join("debian/rules"), "#!/usr/bin/make -f\n").unwrap();
+        fs::create_dir(spec.path().join("patches")).unwrap();
+        fs::write(spec.path().join("patches/series"), "hello.patch\n").unwrap();
+        fs::write(
+            spec.path().join("patches/hello.patch"),
+            "--- a/hello\n+++ b/hello\n@@ -1 +1 @@\n-hello\n+hello, world\n",
+        )
+        .unwrap();
+        for (path, mtime) in &[
+            ("debian/rules", 1_400_000_000),
+            ("patches/series", 1_400_000_000),
+            ("patches/hello.patch", 1_600_000_000),
+        ] {
+            let time = TimeVal::seconds(*mtime);
+            utimes(&spec.path().join(path), &time, &time).unwrap();
+        }
+
+        let mut package = Package::new("foo", spec.path());
+        package.source = vec![
+            Command::Fetch {
+                url: "https://example.com/foo-1.0.tar".to_string(),
+                sha256,
+                dest: ".".to_string(),
+            },
+            Command::Local {
+                path: "debian".to_string(),
+                dest: "foo-1.0/debian".to_string(),
+            },
+            Command::Patches {
+                dir: "patches".to_string(),
+                dest: "foo-1.0".to_string(),
+            },
+        ];
+
+        // every build dates the same, however recently the files were extracted
+        for _ in 0..2 {
+            let build = tempfile::TempDir::new().unwrap();
+            assert_eq!(
+                Some(1_600_000_000),
+                super::extract_sources(cache.path(), &package, build.path()).unwrap()
+            );
+            assert_eq!(
+                "hello, world\n",
+                fs::read_to_string(build.path().join("foo-1.0/hello")).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tempfile_fast::Sponge;
+
+use crate::sources::sha256_file;
+use crate::specs::Command;
+use crate::specs::Package;
+use crate::Release;
+
+/// Bump this if the build process changes in a way which invalidates old results.
+const FORMAT: &str = "fappa-build-v1";
+
+/// What a previous build produced, stored under its key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Record {
+    debs: Vec<Artifact>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Artifact {
+    path: PathBuf,
+    sha256: String,
+}
+
+/// Everything a build's output depends on: the spec, the contents of its sources, the base
+/// image, the packages built for its local dependencies, and whether it's a reproducible build.
+///
+/// Remote sources are pinned by the spec, so only local sources need their contents hashing.
+/// The base image is passed by its sha256, which the caller can reuse between packages.
+pub fn key(
+    package: &Package,
+    release: &Release,
+    base_image: &str,
+    dep_debs: &[PathBuf],
+    reproducible: bool,
+) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    let mut field = |name: &str, value: &str| {
+        hasher.input(name.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(value.as_bytes());
+        hasher.input(b"\0");
+    };
+
+    field("format", FORMAT);
+    field("release", release.codename());
+    field("reproducible", &reproducible.to_string());
+    field("name", &package.name);
+    field("version", &package.version);
+    field("build_dep", &format!("{:?}", package.build_dep));
+    field("source", &format!("{:?}", package.source));
+    field("build", &format!("{:?}", package.build));
+    field("install", &format!("{:?}", package.install));
+    field(
+        "exclude_files",
+        &format!("{:?}", package.exclude_files.sources()),
+    );
+    for output in &package.outputs {
+        field("output", &output.name);
+        field("dep", &format!("{:?}", output.dep));
+        field(
+            "include_files",
+            &format!("{:?}", output.include_files.sources()),
+        );
+    }
+
+    for command in &package.source {
+        let dir = match command {
+            Command::Local { path, .. } => path,
+            Command::Patches { dir, .. } => dir,
+            _ => continue,
+        };
+        field("tree", &hash_tree(package.dir.join(dir))?);
+    }
+
+    field("base_image", base_image);
+
+    for deb in dep_debs {
+        field("dep_deb", &sha256_file(deb)?);
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// A hash of the names, types and contents of everything in a directory.
+fn hash_tree<P: AsRef<Path>>(root: P) -> Result<String, Error> {
+    let root = root.as_ref();
+    let mut hasher = Sha256::new();
+    for entry in walkdir::WalkDir::new(root).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(root)?;
+        hasher.input(relative.to_string_lossy().as_bytes());
+        hasher.input(b"\0");
+
+        let file_type = entry.file_type();
+        if file_type.is_file() {
+            hasher.input(b"f");
+            io::copy(&mut fs::File::open(entry.path())?, &mut hasher)?;
+        } else if file_type.is_symlink() {
+            hasher.input(b"l");
+            hasher.input(fs::read_link(entry.path())?.to_string_lossy().as_bytes());
+        } else {
+            hasher.input(b"d");
+        }
+        hasher.input(b"\0");
+    }
+    Ok(format!("{:x}", hasher.result()))
+}
+
+fn record_path(cache: &Path, key: &str) -> PathBuf {
+    cache.join("results").join(format!("{}.json", key))
+}
+
+/// The `.deb`s from a previous build with this key, if they're all still there, unchanged.
+pub fn lookup<P: AsRef<Path>>(cache: P, key: &str) -> Result<Option<Vec<PathBuf>>, Error> {
+    let path = record_path(cache.as_ref(), key);
+    let record: Record = match fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .with_context(|| format_err!("reading build record {:?}", path))?,
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    for deb in &record.debs {
+        if !deb.path.is_file() || sha256_file(&deb.path)? != deb.sha256 {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(record.debs.into_iter().map(|deb| deb.path).collect()))
+}
+
+pub fn store<P: AsRef<Path>>(cache: P, key: &str, debs: &[PathBuf]) -> Result<(), Error> {
+    let path = record_path(cache.as_ref(), key);
+    fs::create_dir_all(path.parent().expect("joined"))?;
+
+    let record = Record {
+        debs: debs
+            .iter()
+            .map(|path| {
+                Ok(Artifact {
+                    path: path.to_path_buf(),
+                    sha256: sha256_file(path)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+    };
+
+    let mut sponge = Sponge::new_for(&path)?;
+    serde_json::to_writer_pretty(&mut sponge, &record)?;
+    sponge.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::specs::Command;
+    use crate::specs::Package;
+    use crate::Release;
+
+    #[test]
+    fn key() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let image = "0123abcd";
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.c"), "int main() {}").unwrap();
+
+        let mut package = Package::new("foo", dir.path());
+        package.source.push(Command::Local {
+            path: "src".to_string(),
+            dest: ".".to_string(),
+        });
+
+        let key = |package: &Package| {
+            super::key(package, &Release::UbuntuBionic, image, &[], false).unwrap()
+        };
+
+        let original = key(&package);
+        assert_eq!(original, key(&package));
+        assert_ne!(
+            original,
+            super::key(&package, &Release::UbuntuXenial, image, &[], false).unwrap()
+        );
+        assert_ne!(
+            original,
+            super::key(&package, &Release::UbuntuBionic, image, &[], true).unwrap()
+        );
+        assert_ne!(
+            original,
+            super::key(&package, &Release::UbuntuBionic, "4567cdef", &[], false).unwrap()
+        );
+
+        fs::write(dir.path().join("src/main.c"), "int main() { return 1; }").unwrap();
+        let edited = key(&package);
+        assert_ne!(original, edited);
+
+        package.build.push(Command::Run("make".to_string()));
+        assert_ne!(edited, key(&package));
+    }
+
+    #[test]
+    fn lookup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let deb = dir.path().join("foo_0~bionic_amd64.deb");
+        fs::write(&deb, "deb").unwrap();
+
+        assert_eq!(None, super::lookup(dir.path(), "abc").unwrap());
+        super::store(dir.path(), "abc", &[deb.clone()]).unwrap();
+        assert_eq!(
+            Some(vec![deb.clone()]),
+            super::lookup(dir.path(), "abc").unwrap()
+        );
+
+        fs::write(&deb, "changed").unwrap();
+        assert_eq!(None, super::lookup(dir.path(), "abc").unwrap());
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+
+/// The fields of the `control` file which vary between packages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Control {
+    pub package: String,
+    pub version: String,
+    pub depends: Vec<String>,
+    pub description: String,
+}
+
+impl Control {
+    /// `name_version_amd64.deb`, without any epoch, like dpkg names them.
+    pub fn file_name(&self) -> String {
+        let version = match self.version.find(':') {
+            Some(colon) => &self.version[colon + 1..],
+            None => &self.version,
+        };
+        format!("{}_{}_amd64.deb", self.package, version)
+    }
+
+    fn render(&self, installed_size: u64) -> String {
+        let mut ret = String::new();
+        ret.push_str(&format!("Package: {}\n", self.package));
+        ret.push_str(&format!("Version: {}\n", self.version));
+        ret.push_str("Architecture: amd64\n");
+        ret.push_str("Maintainer: fappa <fappa@localhost>\n");
+        ret.push_str(&format!("Installed-Size: {}\n", installed_size));
+        if !self.depends.is_empty() {
+            ret.push_str(&format!("Depends: {}\n", self.depends.join(", ")));
+        }
+        ret.push_str("Section: misc\n");
+        ret.push_str("Priority: optional\n");
+        ret.push_str(&format!("Description: {}\n", self.description));
+        ret
+    }
+}
+
+/// Write a `.deb` containing `files` (absolute paths inside `root`) to `out`.
+///
+/// Everything is owned by root, as that's all dpkg can sensibly do. The parent directories
+/// of the files are included, with their modes from `root`. Like `dpkg-deb`, only regular
+/// files are listed in `md5sums`.
+///
+/// Both tarballs are xz compressed, as older dpkgs can't read zstd.
+///
+/// Entries are always in sorted order. If `clamp` is set, no mtime will be later than it,
+/// as for `SOURCE_DATE_EPOCH`.
+pub fn write<W: Write>(
+    out: W,
+    control: &Control,
+    root: &Path,
+    files: &[&str],
+    clamp: Option<u64>,
+) -> Result<(), Error> {
+    let mut paths = BTreeSet::new();
+    for file in files {
+        ensure!(file.starts_with('/'), "paths must be absolute: {:?}", file);
+        let mut parent = Path::new(file).parent();
+        while let Some(dir) = parent.filter(|dir| Path::new("/") != *dir) {
+            paths.insert(dir.to_str().expect("came from a str").to_string());
+            parent = dir.parent();
+        }
+        paths.insert(file.to_string());
+    }
+
+    let mut md5sums = String::new();
+    let mut installed_size = 0;
+
+    let mut data = tar::Builder::new(xz2::write::XzEncoder::new(Vec::new(), 6));
+
+    for path in &paths {
+        let host = root.join(&path[1..]);
+        let meta = fs::symlink_metadata(&host)
+            .with_context(|| format_err!("reading {:?} for packaging", host))?;
+        let name = &path[1..];
+        let mode = meta.mode() & 0o7777;
+        let mut mtime = meta.mtime().max(0) as u64;
+        if let Some(clamp) = clamp {
+            mtime = mtime.min(clamp);
+        }
+
+        let file_type = meta.file_type();
+        if file_type.is_dir() {
+            let mut header = header(tar::EntryType::Directory, mode, mtime, 0);
+            data.append_data(&mut header, name, io::empty())?;
+            installed_size += 1;
+        } else if file_type.is_symlink() {
+            let mut header = header(tar::EntryType::Symlink, mode, mtime, 0);
+            header.set_link_name(fs::read_link(&host)?)?;
+            data.append_data(&mut header, name, io::empty())?;
+            installed_size += 1;
+        } else if file_type.is_file() {
+            let content = fs::read(&host)?;
+            md5sums.push_str(&format!("{:x}  {}\n", md5::compute(&content), name));
+            let mut header = header(tar::EntryType::Regular, mode, mtime, content.len());
+            data.append_data(&mut header, name, content.as_slice())?;
+            installed_size += (content.len() as u64 + 1023) / 1024;
+        } else {
+            bail!(
+                "can only package files, directories and symlinks: {:?}",
+                path
+            );
+        }
+    }
+
+    let data = data.into_inner()?.finish()?;
+
+    let mut control_tar = tar::Builder::new(xz2::write::XzEncoder::new(Vec::new(), 6));
+    for (name, content) in &[
+        ("control", control.render(installed_size)),
+        ("md5sums", md5sums),
+    ] {
+        let mut header = header(tar::EntryType::Regular, 0o644, 0, content.len());
+        control_tar.append_data(&mut header, name, content.as_bytes())?;
+    }
+    let control_tar = control_tar.into_inner()?.finish()?;
+
+    let mut ar = Ar::new(out)?;
+    ar.append("debian-binary", b"2.0\n")?;
+    ar.append("control.tar.xz", &control_tar)?;
+    ar.append("data.tar.xz", &data)?;
+
+    Ok(())
+}
+
+fn header(kind: tar::EntryType, mode: u32, mtime: u64, size: usize) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+    header.set_size(size as u64);
+    header.set_username("root").expect("static name fits");
+    header.set_groupname("root").expect("static name fits");
+    header
+}
+
+/// Split an `ar` archive, such as a `.deb`, into its members.
+pub fn members(mut ar: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    ensure!(ar.starts_with(b"!<arch>\n"), "not an ar archive");
+    ar = &ar[8..];
+    let mut ret = Vec::new();
+    while !ar.is_empty() {
+        ensure!(ar.len() >= 60, "truncated ar header");
+        let header = String::from_utf8(ar[..60].to_vec())?;
+        ensure!(header.ends_with("`\n"), "invalid ar header: {:?}", header);
+        let size: usize = header[48..58]
+            .trim()
+            .parse()
+            .with_context(|| format_err!("invalid ar member size: {:?}", header))?;
+        let end = 60 + size;
+        ensure!(ar.len() >= end, "truncated ar member");
+        ret.push((header[..16].trim().to_string(), ar[60..end].to_vec()));
+        ar = &ar[(end + size % 2).min(ar.len())..];
+    }
+    Ok(ret)
+}
+
+/// The common (System V / GNU) `ar` format, which is all dpkg accepts.
+struct Ar<W> {
+    inner: W,
+}
+
+impl<W: Write> Ar<W> {
+    fn new(mut inner: W) -> Result<Ar<W>, Error> {
+        inner.write_all(b"!<arch>\n")?;
+        Ok(Ar { inner })
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        ensure!(name.len() <= 16, "ar member name too long: {:?}", name);
+        write!(
+            self.inner,
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            0,
+            0,
+            0,
+            "100644",
+            data.len()
+        )?;
+        self.inner.write_all(data)?;
+        if 1 == data.len() % 2 {
+            self.inner.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::symlink;
+
+    use super::Control;
+
+    /// A package of one script, `/usr/local/bin/foo`, for tests which need a real `.deb`.
+    pub(crate) struct TestPackage {
+        root: tempfile::TempDir,
+    }
+
+    impl TestPackage {
+        pub(crate) fn new(script: &str) -> TestPackage {
+            let root = tempfile::TempDir::new().unwrap();
+            fs::create_dir_all(root.path().join("usr/local/bin")).unwrap();
+            let package = TestPackage { root };
+            package.set_script(script);
+            package
+        }
+
+        /// Rewrite the script, which also moves its mtime forward.
+        pub(crate) fn set_script(&self, script: &str) {
+            fs::write(self.root.path().join("usr/local/bin/foo"), script).unwrap();
+        }
+
+        pub(crate) fn deb(&self, package: &str, version: &str, clamp: Option<u64>) -> Vec<u8> {
+            let control = Control {
+                package: package.to_string(),
+                version: version.to_string(),
+                depends: vec!["libc6".to_string()],
+                description: format!("{} package", package),
+            };
+            let mut out = Vec::new();
+            super::write(
+                &mut out,
+                &control,
+                self.root.path(),
+                &["/usr/local/bin/foo"],
+                clamp,
+            )
+            .unwrap();
+            out
+        }
+    }
+
+    fn untar(xz: &[u8]) -> Vec<(String, u64, String)> {
+        let mut tar = tar::Archive::new(xz2::read::XzDecoder::new(xz));
+        tar.entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                let path = entry.path().unwrap().to_str().unwrap().to_string();
+                (path, entry.header().uid().unwrap(), content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn write() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("usr/local/lib")).unwrap();
+        fs::write(root.join("usr/local/lib/libfoo.so.1"), "hello").unwrap();
+        symlink("libfoo.so.1", root.join("usr/local/lib/libfoo.so")).unwrap();
+
+        let control = Control {
+            package: "libfoo1".to_string(),
+            version: "1.0~bionic".to_string(),
+            depends: vec!["libc6".to_string(), "libbar1 (>= 2)".to_string()],
+            description: "foo library".to_string(),
+        };
+
+        let mut deb = Vec::new();
+        super::write(
+            &mut deb,
+            &control,
+            root,
+            &["/usr/local/lib/libfoo.so.1", "/usr/local/lib/libfoo.so"],
+            None,
+        )
+        .unwrap();
+
+        let members = super::members(&deb).unwrap();
+        assert_eq!(
+            vec!["debian-binary", "control.tar.xz", "data.tar.xz"],
+            members.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(b"2.0\n", members[0].1.as_slice());
+
+        let control = untar(&members[1].1);
+        assert_eq!("control", control[0].0);
+        assert!(control[0].2.contains("\nDepends: libc6, libbar1 (>= 2)\n"));
+        assert!(control[0].2.contains("\nInstalled-Size: 5\n"));
+        assert_eq!(
+            "5d41402abc4b2a76b9719d911017c592  usr/local/lib/libfoo.so.1\n",
+            control[1].2
+        );
+
+        let data = untar(&members[2].1);
+        assert_eq!(
+            vec![
+                "usr",
+                "usr/local",
+                "usr/local/lib",
+                "usr/local/lib/libfoo.so",
+                "usr/local/lib/libfoo.so.1",
+            ],
+            data.iter().map(|(p, _, _)| p.as_str()).collect::<Vec<_>>()
+        );
+        assert!(data.iter().all(|(_, uid, _)| 0 == *uid));
+        assert_eq!("hello", data[4].2);
+    }
+
+    #[test]
+    fn file_name() {
+        let mut control = Control {
+            package: "foo".to_string(),
+            version: "1:2.0~bionic".to_string(),
+            depends: Vec::new(),
+            description: "foo".to_string(),
+        };
+        assert_eq!("foo_2.0~bionic_amd64.deb", control.file_name());
+        control.version = "2.0~bionic".to_string();
+        assert_eq!("foo_2.0~bionic_amd64.deb", control.file_name());
+    }
+
+    #[test]
+    fn clamp() {
+        let package = TestPackage::new("#!/bin/sh\n");
+        let write = || package.deb("foo", "1.0~bionic", Some(1000));
+
+        let first = write();
+        let members = super::members(&first).unwrap();
+        let mut tar = tar::Archive::new(xz2::read::XzDecoder::new(members[2].1.as_slice()));
+        for entry in tar.entries().unwrap() {
+            assert_eq!(1000, entry.unwrap().header().mtime().unwrap());
+        }
+
+        package.set_script("#!/bin/sh\n");
+        assert_eq!(first, write());
+    }
+}
@@ -1,5 +1,6 @@
 use std::ffi::CString;
 use std::fs;
+use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::bail;
@@ -87,6 +88,29 @@ pub fn check_cloned<S: AsRef<str>>(url: S) -> Result<LocalRepo, Error> {
     Ok(LocalRepo { path, specifier })
 }
 
+/// Check out `sha`, from the cached clone of `repo`, into `dest`, returning its commit date.
+pub fn export<P: AsRef<Path>>(repo: &str, sha: &str, dest: P) -> Result<i64, Error> {
+    let local = check_cloned(format!("{}?rev={}", repo, sha))?;
+    let GitSpecifier::Hash(oid) = local.specifier;
+
+    let repo = git2::Repository::open_bare(Path::new(".cache").join(&local.path))
+        .with_context(|| format_err!("opening cache repo"))?;
+    let commit = repo.find_commit(oid)?;
+
+    fs::create_dir_all(&dest)?;
+    repo.checkout_tree(
+        commit.as_object(),
+        Some(
+            git2::build::CheckoutBuilder::new()
+                .target_dir(dest.as_ref())
+                .force(),
+        ),
+    )
+    .with_context(|| format_err!("checking out {} to {:?}", oid, dest.as_ref()))?;
+
+    Ok(commit.time().seconds())
+}
+
 fn check_single(url: &Url, specifier: GitSpecifier) -> Result<(git2::Repository, String), Error> {
     let mut path = PathBuf::from(".cache");
     let safe_url = fs_safe_url(&url);
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Error;
+
+use crate::specs::Package;
+
+/// The package names mentioned in a `dep` or `build_dep` entry,
+/// e.g. `libfoo-dev (>= 1.2) | libbar-dev` mentions `libfoo-dev` and `libbar-dev`.
+pub fn mentioned(dep: &str) -> impl Iterator<Item = &str> {
+    dep.split('|').filter_map(|alternative| {
+        alternative
+            .trim()
+            .split(|c: char| c.is_whitespace() || '(' == c || ':' == c)
+            .next()
+            .filter(|name| !name.is_empty())
+    })
+}
+
+/// The local package which produces the binary package `name`, if any.
+pub fn producer<'l>(name: &str, local: &'l [Package]) -> Option<&'l Package> {
+    local
+        .iter()
+        .find(|p| p.outputs.iter().any(|o| o.name == name))
+}
+
+/// Names of the packages in `local` which `package` needs, to build or to run.
+///
+/// An output depending on a sibling output of the same package isn't a dependency.
+pub fn local_deps<'l>(package: &Package, local: &'l [Package]) -> Vec<&'l str> {
+    let mut ret = Vec::new();
+    for name in package
+        .build_dep
+        .iter()
+        .chain(package.outputs.iter().flat_map(|o| &o.dep))
+        .flat_map(|dep| mentioned(dep))
+    {
+        let producer = match producer(name, local) {
+            Some(producer) => producer.name.as_str(),
+            None => continue,
+        };
+        if producer != package.name && !ret.contains(&producer) {
+            ret.push(producer);
+        }
+    }
+    ret
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Mark {
+    InProgress,
+    Done,
+}
+
+/// Sort `packages` such that every package comes after the local packages it depends on.
+///
+/// Otherwise, the input order is preserved.
+pub fn build_order(packages: Vec<Package>) -> Result<Vec<Package>, Error> {
+    let mut index = HashMap::with_capacity(packages.len());
+    for (i, package) in packages.iter().enumerate() {
+        ensure!(
+            index.insert(package.name.as_str(), i).is_none(),
+            "package {:?} is defined more than once",
+            package.name
+        );
+    }
+
+    let mut outputs = HashMap::new();
+    for package in &packages {
+        for output in &package.outputs {
+            if let Some(other) = outputs.insert(output.name.as_str(), package.name.as_str()) {
+                bail!(
+                    "output {:?} is produced by both {:?} and {:?}",
+                    output.name,
+                    other,
+                    package.name
+                );
+            }
+        }
+    }
+
+    let mut marks = vec![None; packages.len()];
+    let mut order = Vec::with_capacity(packages.len());
+    let mut path = Vec::new();
+
+    for i in 0..packages.len() {
+        visit(&packages, &index, i, &mut marks, &mut order, &mut path)?;
+    }
+
+    let mut packages: Vec<Option<Package>> = packages.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .map(|i| packages[i].take().expect("visited once"))
+        .collect())
+}
+
+fn visit<'p>(
+    packages: &'p [Package],
+    index: &HashMap<&str, usize>,
+    i: usize,
+    marks: &mut [Option<Mark>],
+    order: &mut Vec<usize>,
+    path: &mut Vec<&'p str>,
+) -> Result<(), Error> {
+    let package = &packages[i];
+    match marks[i] {
+        Some(Mark::Done) => return Ok(()),
+        Some(Mark::InProgress) => {
+            let start = path
+                .iter()
+                .position(|&name| name == package.name)
+                .expect("in progress implies on the path");
+            let mut cycle = path[start..].to_vec();
+            cycle.push(&package.name);
+            bail!("dependency cycle: {}", cycle.join(" -> "));
+        }
+        None => (),
+    }
+
+    marks[i] = Some(Mark::InProgress);
+    path.push(&package.name);
+
+    for dep in local_deps(package, packages) {
+        visit(packages, index, index[dep], marks, order, path)?;
+    }
+
+    path.pop();
+    marks[i] = Some(Mark::Done);
+    order.push(i);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::specs::Output;
+    use crate::specs::Package;
+
+    fn package(name: &str, build_dep: &[&str]) -> Package {
+        let mut package = Package::new(name, ".");
+        package.build_dep = build_dep.iter().map(|s| s.to_string()).collect();
+        package
+    }
+
+    fn names(packages: &[Package]) -> Vec<&str> {
+        packages.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn mentioned() {
+        assert_eq!(
+            vec!["libfoo-dev", "libbar-dev"],
+            super::mentioned("libfoo-dev (>= 1.2) | libbar-dev:amd64").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn deps_first() {
+        let order = super::build_order(vec![
+            package("libsigrok", &["libglib2.0-dev", "libftdi1-dev (>= 1)"]),
+            package("pulseview", &["libsigrok"]),
+            package("libftdi1-dev", &["cmake"]),
+        ])
+        .unwrap();
+        assert_eq!(
+            vec!["libftdi1-dev", "libsigrok", "pulseview"],
+            names(&order)
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let err = super::build_order(vec![
+            package("a", &["b"]),
+            package("b", &["c"]),
+            package("c", &["a"]),
+        ])
+        .unwrap_err();
+        assert_eq!("dependency cycle: a -> b -> c -> a", err.to_string());
+    }
+
+    #[test]
+    fn duplicates() {
+        assert!(super::build_order(vec![package("a", &[]), package("a", &[])]).is_err());
+    }
+
+    #[test]
+    fn outputs() {
+        let mut lib = package("libfoo", &[]);
+        lib.outputs = vec![Output::new("libfoo1"), Output::new("libfoo-dev")];
+        lib.outputs[1].dep = vec!["libfoo1".to_string()];
+
+        let order = super::build_order(vec![package("app", &["libfoo-dev"]), lib]).unwrap();
+        assert_eq!(vec!["libfoo", "app"], names(&order));
+
+        let mut clash = package("other", &[]);
+        clash.outputs = vec![Output::new("libfoo1")];
+        let mut lib = package("libfoo", &[]);
+        lib.outputs = vec![Output::new("libfoo1")];
+        assert!(super::build_order(vec![lib, clash]).is_err());
+    }
+}
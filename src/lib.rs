@@ -1,13 +1,25 @@
 pub mod build;
+pub mod cache;
+pub mod deb;
 pub mod fetch_images;
 #[cfg(feature = "git2")]
 pub mod git;
+pub mod graph;
+pub mod logs;
 pub mod namespace;
+pub mod patch;
+pub mod patterns;
+pub mod repo;
+pub mod repro;
+pub mod schedule;
+pub mod shlibs;
+pub mod snapshot;
+pub mod sources;
 pub mod spec_sh;
 pub mod specs;
 pub mod unpack;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Release {
     DebianJessie,
     DebianStretch,
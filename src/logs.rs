@@ -0,0 +1,291 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use log::info;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use tempfile_fast::Sponge;
+
+use crate::namespace::cgroup::Usage;
+use crate::namespace::child::Sink;
+
+/// The record of one build, stored as `meta.json` next to the raw `build.log`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metadata {
+    pub package: String,
+    pub release: String,
+    pub run: String,
+    /// Seconds since the epoch.
+    pub start: u64,
+    pub end: Option<u64>,
+    pub phases: Vec<PhaseRecord>,
+    /// Messages from the sandbox's init, rather than from the build.
+    pub debug: Vec<String>,
+    pub error: Option<String>,
+    /// Only recorded for sandboxes with resource limits.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PhaseRecord {
+    pub name: String,
+    pub start: u64,
+    pub end: Option<u64>,
+    pub ok: bool,
+    pub commands: Vec<CommandRecord>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub command: String,
+    pub exit_code: u8,
+}
+
+/// Collects a build's output, and what it was doing at the time.
+///
+/// Output is also copied to stdout, so it can be watched as it happens.
+pub struct BuildLog {
+    dir: PathBuf,
+    raw: fs::File,
+    meta: Metadata,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn runs_dir(cache: &Path, package: &str, release: &str) -> PathBuf {
+    cache.join("logs").join(package).join(release)
+}
+
+impl BuildLog {
+    pub fn create<P: AsRef<Path>>(
+        cache: P,
+        package: &str,
+        release: &str,
+    ) -> Result<BuildLog, Error> {
+        let start = now();
+        let base = runs_dir(cache.as_ref(), package, release);
+
+        // runs are named by their start time, so sort in order
+        let mut run = format!("{:012}", start);
+        let mut suffix = 1;
+        while base.join(&run).exists() {
+            run = format!("{:012}.{}", start, suffix);
+            suffix += 1;
+        }
+
+        let dir = base.join(&run);
+        fs::create_dir_all(&dir)?;
+        let raw = fs::File::create(dir.join("build.log"))?;
+
+        let log = BuildLog {
+            dir,
+            raw,
+            meta: Metadata {
+                package: package.to_string(),
+                release: release.to_string(),
+                run,
+                start,
+                end: None,
+                phases: Vec::new(),
+                debug: Vec::new(),
+                error: None,
+                usage: None,
+            },
+        };
+        log.save()?;
+        Ok(log)
+    }
+
+    pub fn start_phase(&mut self, name: &str) -> Result<(), Error> {
+        writeln!(self.raw, "fappa: starting {} phase", name)?;
+        self.meta.phases.push(PhaseRecord {
+            name: name.to_string(),
+            start: now(),
+            end: None,
+            ok: false,
+            commands: Vec::new(),
+        });
+        self.save()
+    }
+
+    pub fn command(&mut self, command: &str, exit_code: u8) -> Result<(), Error> {
+        writeln!(
+            self.raw,
+            "fappa: exit code {} from `{}`",
+            exit_code, command
+        )?;
+        let phase = self
+            .meta
+            .phases
+            .last_mut()
+            .ok_or_else(|| anyhow!("command outside of a phase: {:?}", command))?;
+        phase.commands.push(CommandRecord {
+            command: command.to_string(),
+            exit_code,
+        });
+        Ok(())
+    }
+
+    /// The current phase succeeded. Phases which are never ended are failures.
+    pub fn end_phase(&mut self) -> Result<(), Error> {
+        let phase = self
+            .meta
+            .phases
+            .last_mut()
+            .ok_or_else(|| anyhow!("no phase to end"))?;
+        phase.end = Some(now());
+        phase.ok = true;
+        info!(
+            "{} on {}: {} phase ok",
+            self.meta.package, self.meta.release, phase.name
+        );
+        self.save()
+    }
+
+    pub fn usage(&mut self, usage: Usage) -> Result<(), Error> {
+        self.meta.usage = Some(usage);
+        self.save()
+    }
+
+    pub fn finish<T>(mut self, result: &Result<T, Error>) -> Result<PathBuf, Error> {
+        let end = now();
+        for phase in &mut self.meta.phases {
+            phase.end.get_or_insert(end);
+        }
+        self.meta.end = Some(end);
+        if let Err(e) = result {
+            self.meta.error = Some(format!("{:?}", e));
+            writeln!(self.raw, "fappa: failed: {:?}", e)?;
+        }
+        self.save()?;
+        Ok(self.dir)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let mut sponge = Sponge::new_for(self.dir.join("meta.json"))?;
+        serde_json::to_writer_pretty(&mut sponge, &self.meta)?;
+        sponge.commit()?;
+        Ok(())
+    }
+}
+
+impl Sink for BuildLog {
+    fn output(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.raw.write_all(data)?;
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(data)?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn debug(&mut self, msg: &str) -> Result<(), Error> {
+        info!("child says: {}", msg);
+        self.meta.debug.push(msg.to_string());
+        Ok(())
+    }
+}
+
+/// The runs recorded for this package and release, oldest first.
+pub fn runs<P: AsRef<Path>>(cache: P, package: &str, release: &str) -> Result<Vec<String>, Error> {
+    let base = runs_dir(cache.as_ref(), package, release);
+    let mut ret = Vec::new();
+    let entries = match fs::read_dir(&base) {
+        Ok(entries) => entries,
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => return Ok(ret),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        if let Some(name) = entry?.file_name().to_str() {
+            ret.push(name.to_string());
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// The metadata and raw log path of a run, or of the latest run.
+pub fn open<P: AsRef<Path>>(
+    cache: P,
+    package: &str,
+    release: &str,
+    run: Option<&str>,
+) -> Result<(Metadata, PathBuf), Error> {
+    let cache = cache.as_ref();
+    let available = runs(cache, package, release)?;
+    let run = match run {
+        Some(run) => available.iter().find(|r| r.as_str() == run),
+        None => available.last(),
+    }
+    .ok_or_else(|| {
+        format_err!(
+            "no such run of {} on {}; available: {:?}",
+            package,
+            release,
+            available
+        )
+    })?;
+
+    let dir = runs_dir(cache, package, release).join(run);
+    let meta = dir.join("meta.json");
+    let meta = serde_json::from_slice(&fs::read(&meta)?)
+        .with_context(|| format_err!("reading {:?}", meta))?;
+    Ok((meta, dir.join("build.log")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::namespace::child::Sink;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut log = super::BuildLog::create(dir.path(), "foo", "bionic").unwrap();
+        log.start_phase("build").unwrap();
+        log.output(b"compiling\n").unwrap();
+        log.debug("init is alive").unwrap();
+        log.command("make", 0).unwrap();
+        log.end_phase().unwrap();
+        log.start_phase("install").unwrap();
+        log.command("make install", 2).unwrap();
+        log.finish::<()>(&Err(anyhow::anyhow!("install phase failed")))
+            .unwrap();
+
+        // a second run, in the same second, doesn't clobber the first
+        let second = super::BuildLog::create(dir.path(), "foo", "bionic").unwrap();
+        second.finish(&Ok(())).unwrap();
+
+        let runs = super::runs(dir.path(), "foo", "bionic").unwrap();
+        assert_eq!(2, runs.len());
+
+        let (meta, log) = super::open(dir.path(), "foo", "bionic", Some(&runs[0])).unwrap();
+        assert_eq!(vec!["init is alive"], meta.debug);
+        assert_eq!(2, meta.phases.len());
+        assert!(meta.phases[0].ok);
+        assert!(!meta.phases[1].ok);
+        assert_eq!(2, meta.phases[1].commands[0].exit_code);
+        assert!(meta.error.unwrap().contains("install phase failed"));
+        assert!(fs::read_to_string(log).unwrap().contains("compiling\n"));
+
+        let (latest, _) = super::open(dir.path(), "foo", "bionic", None).unwrap();
+        assert_eq!(runs[1], latest.run);
+        assert!(super::open(dir.path(), "foo", "xenial", None).is_err());
+    }
+}
@@ -4,6 +4,7 @@ use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process;
 
 use anyhow::ensure;
@@ -15,8 +16,15 @@ use log::error;
 use log::info;
 use void::ResultVoidErrExt;
 
+pub mod cgroup;
 pub mod child;
+pub mod config;
+pub mod dev;
 mod id_map;
+pub mod net;
+
+use config::Config;
+use config::Network;
 
 pub fn unpack_to_temp<P: AsRef<Path>>(cache: P, distro: &str) -> Result<tempfile::TempDir, Error> {
     let mut root = super::fetch_images::base_image(cache, distro)?;
@@ -29,7 +37,17 @@ pub fn unpack_to_temp<P: AsRef<Path>>(cache: P, distro: &str) -> Result<tempfile
     Ok(temp)
 }
 
-pub fn launch_our_init<P: AsRef<Path>>(root: P) -> Result<child::Child, Error> {
+/// Start a sandbox in `root`, with the binds from `config`, inside a new cgroup if there
+/// are any limits.
+pub fn launch_our_init<P: AsRef<Path>>(root: P, config: &Config) -> Result<child::Child, Error> {
+    config.validate(root.as_ref())?;
+
+    let cgroup = if config.limits.is_empty() {
+        None
+    } else {
+        Some(cgroup::Cgroup::create(&config.limits).with_context(|| anyhow!("creating cgroup"))?)
+    };
+
     let (from_recv, from_send) = os_pipe::pipe()?;
     let (into_recv, into_send) = os_pipe::pipe()?;
 
@@ -37,15 +55,25 @@ pub fn launch_our_init<P: AsRef<Path>>(root: P) -> Result<child::Child, Error> {
         let mut finit_host = root.as_ref().to_path_buf();
         finit_host.push("bin");
         finit_host.push("finit");
-        reflink::reflink_or_copy("target/x86_64-unknown-linux-musl/debug/finit", &finit_host)
-            .with_context(|| anyhow!("copying init from host to child"))?;
+        let finit = find_init()?;
+        reflink::reflink_or_copy(&finit, &finit_host)
+            .with_context(|| format_err!("copying init from {:?} to child", finit))?;
         fs::set_permissions(&finit_host, fs::Permissions::from_mode(0o755))?;
         info!("finit written to {:?}", finit_host);
 
         let mut resolv_conf_host = root.as_ref().to_path_buf();
         resolv_conf_host.push("etc");
         resolv_conf_host.push("resolv.conf");
-        fs::write(resolv_conf_host, b"nameserver 127.0.0.53")?;
+        let resolv_conf = match config.network {
+            Network::Host => net::host_resolv_conf()?,
+            Network::Loopback => "# no network in this sandbox\n".to_string(),
+        };
+        // images often ship it as a symlink, which we must not follow out onto the host
+        match fs::symlink_metadata(&resolv_conf_host) {
+            Ok(ref meta) if meta.file_type().is_symlink() => fs::remove_file(&resolv_conf_host)?,
+            _ => (),
+        }
+        fs::write(resolv_conf_host, resolv_conf)?;
     }
 
     let first_fork = {
@@ -53,7 +81,7 @@ pub fn launch_our_init<P: AsRef<Path>>(root: P) -> Result<child::Child, Error> {
         match fork()? {
             ForkResult::Parent { child } => child,
             ForkResult::Child => {
-                let e = setup_namespace(&root, into_recv, from_send).void_unwrap_err();
+                let e = setup_namespace(&root, config, into_recv, from_send).void_unwrap_err();
                 error!("sandbox setup failed: {:?}", e);
                 process::exit(67);
             }
@@ -66,6 +94,11 @@ pub fn launch_our_init<P: AsRef<Path>>(root: P) -> Result<child::Child, Error> {
         _types: Default::default(),
     };
 
+    // before the sandbox gets a chance to start anything
+    if let Some(cgroup) = &cgroup {
+        cgroup.join(first_fork)?;
+    }
+
     proto.init_await_map_command()?;
 
     id_map::map_us(first_fork)?;
@@ -75,9 +108,57 @@ pub fn launch_our_init<P: AsRef<Path>>(root: P) -> Result<child::Child, Error> {
     Ok(child::Child {
         proto,
         pid: first_fork,
+        cgroup,
     })
 }
 
+/// The init to run inside sandboxes: `$FAPPA_FINIT`, or `finit` next to our binary,
+/// or, when we're running from a checkout, the musl build of it from `just finit`.
+///
+/// It runs inside arbitrary distros, so it must be statically linked.
+pub fn find_init() -> Result<PathBuf, Error> {
+    let mut candidates = Vec::new();
+    if let Some(path) = env::var_os("FAPPA_FINIT") {
+        candidates.push(PathBuf::from(path));
+    }
+
+    let exe = env::current_exe()?;
+    if let Some(dir) = exe.parent() {
+        candidates.push(dir.join("finit"));
+
+        // target/debug/fappa -> target/x86_64-unknown-linux-musl/debug/finit
+        if let (Some(profile), Some(target)) = (dir.file_name(), dir.parent()) {
+            candidates.push(
+                target
+                    .join("x86_64-unknown-linux-musl")
+                    .join(profile)
+                    .join("finit"),
+            );
+        }
+    }
+
+    let finit = candidates
+        .iter()
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            format_err!(
+                "no finit found (try `just finit`), looked for: {:?}",
+                candidates
+            )
+        })?;
+
+    let elf = fs::read(finit)?;
+    let elf =
+        goblin::elf::Elf::parse(&elf).with_context(|| format_err!("reading {:?} as ELF", finit))?;
+    ensure!(
+        elf.interpreter.is_none(),
+        "{:?} is dynamically linked, but must be static; build it for musl",
+        finit
+    );
+
+    Ok(finit.to_path_buf())
+}
+
 fn reopen_stdin_as_null() -> Result<(), Error> {
     nix::unistd::dup3(
         fs::File::open("/dev/null")?.as_raw_fd(),
@@ -90,6 +171,7 @@ fn reopen_stdin_as_null() -> Result<(), Error> {
 
 fn setup_namespace<P: AsRef<Path>>(
     root: P,
+    config: &Config,
     mut recv: os_pipe::PipeReader,
     mut send: os_pipe::PipeWriter,
 ) -> Result<void::Void, Error> {
@@ -99,14 +181,15 @@ fn setup_namespace<P: AsRef<Path>>(
 
     {
         use nix::sched::*;
-        unshare(
-            CloneFlags::CLONE_NEWIPC
-                | CloneFlags::CLONE_NEWNS
-                | CloneFlags::CLONE_NEWPID
-                | CloneFlags::CLONE_NEWUSER
-                | CloneFlags::CLONE_NEWUTS,
-        )
-        .with_context(|| anyhow!("unshare"))?;
+        let mut flags = CloneFlags::CLONE_NEWIPC
+            | CloneFlags::CLONE_NEWNS
+            | CloneFlags::CLONE_NEWPID
+            | CloneFlags::CLONE_NEWUSER
+            | CloneFlags::CLONE_NEWUTS;
+        if Network::Loopback == config.network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+        unshare(flags).with_context(|| anyhow!("unshare"))?;
     }
 
     {
@@ -156,15 +239,17 @@ fn setup_namespace<P: AsRef<Path>>(
         )
         .with_context(|| anyhow!("mount --bind /sys sys"))?;
 
-        drop(fs::File::create("dev/null")?);
-        mount(
-            Some("/dev/null"),
-            "dev/null",
-            unset,
-            MsFlags::MS_BIND,
-            unset,
-        )
-        .with_context(|| anyhow!("mount --bind /dev/null"))?;
+        if !config.dev {
+            drop(fs::File::create("dev/null")?);
+            mount(
+                Some("/dev/null"),
+                "dev/null",
+                unset,
+                MsFlags::MS_BIND,
+                unset,
+            )
+            .with_context(|| anyhow!("mount --bind /dev/null"))?;
+        }
     }
 
     child::Proto::<u64, u64>::await_maps(&mut send, &mut recv)?;
@@ -176,6 +261,43 @@ fn setup_namespace<P: AsRef<Path>>(
 
     setgroups(&[Gid::from_raw(0)]).with_context(|| anyhow!("setgroups(0)"))?;
 
+    if Network::Loopback == config.network {
+        net::loopback_up()?;
+    }
+
+    // devpts needs our group mapped, and the binds go on top of everything else
+    {
+        let unset: Option<&str> = None;
+        use nix::mount::*;
+
+        if config.dev {
+            dev::populate().with_context(|| anyhow!("populating /dev"))?;
+        }
+
+        for path in &config.tmpfs {
+            let relative = path.strip_prefix("/").expect("validated to be absolute");
+            fs::create_dir_all(relative)?;
+            dev::tmpfs(relative)?;
+        }
+
+        for bind in &config.binds {
+            make_bind_destination(bind)?;
+            let mounted = if bind.writable {
+                mount(
+                    Some(&bind.host),
+                    bind.relative(),
+                    unset,
+                    MsFlags::MS_BIND | MsFlags::MS_REC,
+                    unset,
+                )
+                .map_err(Error::from)
+            } else {
+                bind_read_only(&bind.host, bind.relative())
+            };
+            mounted.with_context(|| format_err!("binding {:?} to {:?}", bind.host, bind.inside))?;
+        }
+    }
+
     make_mount_destination("old")?;
     pivot_root(&Some("."), &Some("old")).with_context(|| anyhow!("pivot_root"))?;
     nix::mount::umount2("old", nix::mount::MntFlags::MNT_DETACH)
@@ -228,7 +350,6 @@ fn setup_pid_1(recv: os_pipe::PipeReader, send: os_pipe::PipeWriter) -> Result<v
             .with_context(|| anyhow!("permissions for /tmp"))?;
         fs::set_permissions("/var/tmp", sticky_for_all)
             .with_context(|| anyhow!("permissions for /var/tmp"))?;
-        // TODO: dev/shm?
     }
 
     {
@@ -272,6 +393,77 @@ fn setup_pid_1(recv: os_pipe::PipeReader, send: os_pipe::PipeWriter) -> Result<v
     );
 }
 
+/// Create somewhere for `bind` to be mounted on, inside the root, which is our working directory.
+///
+/// The root came from an image, so none of the path may be a symlink, which could point
+/// anywhere on the host.
+fn make_bind_destination(bind: &config::Bind) -> Result<(), Error> {
+    let dest = bind.relative();
+    let mut so_far = PathBuf::new();
+    for component in dest.components() {
+        so_far.push(component);
+        match fs::symlink_metadata(&so_far) {
+            Ok(meta) => ensure!(
+                !meta.file_type().is_symlink(),
+                "bind destination {:?} goes through a symlink: {:?}",
+                bind.inside,
+                so_far
+            ),
+            Err(_) => break,
+        }
+    }
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::metadata(&bind.host)?.is_dir() {
+        if !dest.is_dir() {
+            fs::create_dir(dest)
+                .with_context(|| format_err!("creating bind destination {:?}", bind.inside))?;
+        }
+    } else if !dest.is_file() {
+        drop(fs::File::create(dest)?);
+    }
+    Ok(())
+}
+
+/// Bind `src` onto `dest`, then make it read-only.
+///
+/// Inside a user namespace, the remount must keep the flags the host mount is locked with.
+fn bind_read_only<S: AsRef<Path>, D: AsRef<Path>>(src: S, dest: D) -> Result<(), Error> {
+    let unset: Option<&str> = None;
+    use nix::mount::*;
+    use nix::sys::statvfs::*;
+
+    mount(
+        Some(src.as_ref()),
+        dest.as_ref(),
+        unset,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        unset,
+    )
+    .with_context(|| anyhow!("mount --bind"))?;
+
+    let host = statvfs(src.as_ref())?.flags();
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (has, keep) in &[
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if host.contains(*has) {
+            flags |= *keep;
+        }
+    }
+
+    mount(unset, dest.as_ref(), unset, flags, unset)
+        .with_context(|| anyhow!("remounting read-only"))?;
+    Ok(())
+}
+
 fn make_mount_destination(name: &'static str) -> Result<(), Error> {
     let _ = fs::remove_dir(name);
     fs::create_dir(name)
@@ -0,0 +1,321 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::anyhow;
+use anyhow::format_err;
+use anyhow::Error;
+use anyhow::Context;
+use log::info;
+use log::warn;
+use nix::unistd::Pid;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+const MOUNT: &str = "/sys/fs/cgroup";
+
+/// Resource limits for a sandbox. Nothing is limited by default, and then no cgroup is needed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Bytes, for `memory.max`.
+    pub memory: Option<u64>,
+    /// Whole or fractional cpus, for `cpu.max`.
+    pub cpus: Option<f64>,
+    /// Processes and threads, for `pids.max`.
+    pub pids: Option<u64>,
+    /// 1 to 10000, relative to other cgroups, for `io.weight`.
+    pub io_weight: Option<u16>,
+    /// The delegated cgroup to create ours under; by default, the one we're running in.
+    ///
+    /// e.g. `systemd-run --user --scope -p Delegate=yes fappa build`
+    pub parent: Option<PathBuf>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none()
+            && self.cpus.is_none()
+            && self.pids.is_none()
+            && self.io_weight.is_none()
+    }
+
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut ret = Vec::new();
+        if self.memory.is_some() {
+            ret.push("memory");
+        }
+        if self.cpus.is_some() {
+            ret.push("cpu");
+        }
+        if self.pids.is_some() {
+            ret.push("pids");
+        }
+        if self.io_weight.is_some() {
+            ret.push("io");
+        }
+        ret
+    }
+
+    /// The file, and value, to write for each limit.
+    fn settings(&self) -> Vec<(&'static str, String)> {
+        let mut ret = Vec::new();
+        if let Some(memory) = self.memory {
+            ret.push(("memory.max", memory.to_string()));
+            // otherwise the limit just moves the build into swap
+            ret.push(("memory.swap.max", "0".to_string()));
+        }
+        if let Some(cpus) = self.cpus {
+            let period = 100_000;
+            let quota = (cpus * f64::from(period)).round() as u64;
+            ret.push(("cpu.max", format!("{} {}", quota, period)));
+        }
+        if let Some(pids) = self.pids {
+            ret.push(("pids.max", pids.to_string()));
+        }
+        if let Some(weight) = self.io_weight {
+            ret.push(("io.weight", format!("default {}", weight)));
+        }
+        ret
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(cpus) = self.cpus {
+            ensure!(cpus >= 0.01, "cpu limit is too small: {}", cpus);
+        }
+        if let Some(weight) = self.io_weight {
+            ensure!(
+                (1..=10000).contains(&weight),
+                "io weight must be between 1 and 10000: {}",
+                weight
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What a sandbox used, while it was running.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Bytes. Only available on newer kernels.
+    pub peak_memory: Option<u64>,
+    pub cpu_usec: Option<u64>,
+}
+
+/// A cgroup for one sandbox, removed when dropped.
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Make a new cgroup, under the delegated parent, with these limits applied.
+    pub fn create(limits: &Limits) -> Result<Cgroup, Error> {
+        let parent = prepare(limits)?;
+
+        let path = parent.join(format!("fappa-{}", process::id()));
+        fs::create_dir(&path).with_context(|| format_err!("creating cgroup {:?}", path))?;
+        let cgroup = Cgroup { path };
+
+        for (file, value) in limits.settings() {
+            let target = cgroup.path.join(file);
+            match fs::write(&target, &value) {
+                Ok(()) => (),
+                // there's no swap accounting, so no swap to limit
+                Err(ref e) if "memory.swap.max" == file && io::ErrorKind::NotFound == e.kind() => {
+                    continue
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format_err!("writing {:?} to {:?}", value, target))
+                }
+            }
+        }
+
+        info!("limiting sandbox with {:?}", cgroup.path);
+        Ok(cgroup)
+    }
+
+    /// Move a process, and hence everything it later starts, into the cgroup.
+    pub fn join(&self, pid: Pid) -> Result<(), Error> {
+        fs::write(self.path.join("cgroup.procs"), pid.to_string())
+            .with_context(|| format_err!("moving {} into {:?}", pid, self.path))?;
+        Ok(())
+    }
+
+    pub fn usage(&self) -> Result<Usage, Error> {
+        let peak_memory = match fs::read_to_string(self.path.join("memory.peak")) {
+            Ok(peak) => Some(peak.trim().parse()?),
+            Err(ref e) if io::ErrorKind::NotFound == e.kind() => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let cpu_usec = fs::read_to_string(self.path.join("cpu.stat"))?
+            .lines()
+            .find_map(|line| line.strip_prefix("usage_usec "))
+            .map(|usec| usec.trim().parse())
+            .transpose()?;
+
+        Ok(Usage {
+            peak_memory,
+            cpu_usec,
+        })
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir(&self.path) {
+            warn!("couldn't remove cgroup {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+/// Get the delegated parent ready for sandboxes with these limits, returning its path.
+///
+/// This moves us out of the parent, so do it once, before starting any workers, and pass
+/// them the result as their `parent`. A parent which is already prepared isn't touched.
+pub fn prepare(limits: &Limits) -> Result<PathBuf, Error> {
+    limits.validate()?;
+    ensure!(
+        Path::new(MOUNT).join("cgroup.controllers").is_file(),
+        "resource limits need cgroup v2 mounted at {}",
+        MOUNT
+    );
+
+    let parent = match &limits.parent {
+        Some(parent) => parent.to_path_buf(),
+        None => Path::new(MOUNT).join(own_cgroup()?),
+    };
+
+    let enabled = fs::read_to_string(parent.join("cgroup.subtree_control"))
+        .with_context(|| format_err!("reading enabled controllers of {:?}", parent))?;
+    let enabled: Vec<&str> = enabled.split_whitespace().collect();
+    if limits.controllers().iter().all(|c| enabled.contains(c)) {
+        return Ok(parent);
+    }
+
+    let available = fs::read_to_string(parent.join("cgroup.controllers"))
+        .with_context(|| format_err!("reading controllers of {:?}", parent))?;
+    let available: Vec<&str> = available.split_whitespace().collect();
+    for controller in limits.controllers() {
+        ensure!(
+            available.contains(&controller),
+            "the {} controller isn't delegated to {:?}; try \
+             `systemd-run --user --scope -p Delegate=yes`",
+            controller,
+            parent
+        );
+    }
+
+    evacuate(&parent)?;
+
+    let enable = limits
+        .controllers()
+        .iter()
+        .map(|c| format!("+{}", c))
+        .collect::<Vec<_>>()
+        .join(" ");
+    fs::write(parent.join("cgroup.subtree_control"), &enable)
+        .with_context(|| format_err!("enabling {:?} in {:?}", enable, parent))?;
+
+    Ok(parent)
+}
+
+/// The cgroup we're in, relative to the mount point, e.g. `user.slice/.../run-u123.scope`.
+fn own_cgroup() -> Result<String, Error> {
+    let text = fs::read_to_string("/proc/self/cgroup")?;
+    for line in text.lines() {
+        if let Some(path) = line.strip_prefix("0::/") {
+            return Ok(path.to_string());
+        }
+    }
+    bail!("not in a cgroup v2 hierarchy: {:?}", text)
+}
+
+/// Controllers can only be enabled for a cgroup's children if it has no processes of its own,
+/// so move anything in the parent (i.e. us, and the rest of fappa) into a leaf.
+fn evacuate(parent: &Path) -> Result<(), Error> {
+    let procs = fs::read_to_string(parent.join("cgroup.procs"))?;
+    if procs.trim().is_empty() {
+        return Ok(());
+    }
+
+    let leaf = parent.join("fappa-supervisor");
+    match fs::create_dir(&leaf) {
+        Ok(()) => (),
+        Err(ref e) if io::ErrorKind::AlreadyExists == e.kind() => (),
+        Err(e) => return Err(e).with_context(|| format_err!("creating {:?}", leaf)),
+    }
+
+    for pid in procs.split_whitespace() {
+        match fs::write(leaf.join("cgroup.procs"), pid) {
+            Ok(()) => (),
+            // it exited while we were looking
+            Err(ref e) if Some(libc::ESRCH) == e.raw_os_error() => (),
+            Err(e) => return Err(e).with_context(|| anyhow!("moving {} to {:?}", pid, leaf)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sizes like `4G` or `512M`, in bytes.
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format_err!("invalid size: {:?}", size))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format_err!("size too large: {:?}", size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Limits;
+
+    #[test]
+    fn parse_size() {
+        assert_eq!(123, super::parse_size("123").unwrap());
+        assert_eq!(4 << 30, super::parse_size("4G").unwrap());
+        assert_eq!(512 << 20, super::parse_size("512m").unwrap());
+        assert!(super::parse_size("M").is_err());
+        assert!(super::parse_size("-1").is_err());
+    }
+
+    #[test]
+    fn settings() {
+        assert!(Limits::default().is_empty());
+        let limits = Limits {
+            memory: Some(1 << 30),
+            cpus: Some(1.5),
+            pids: None,
+            io_weight: Some(50),
+            parent: None,
+        };
+        assert_eq!(vec!["memory", "cpu", "io"], limits.controllers());
+        assert_eq!(
+            vec![
+                ("memory.max", "1073741824".to_string()),
+                ("memory.swap.max", "0".to_string()),
+                ("cpu.max", "150000 100000".to_string()),
+                ("io.weight", "default 50".to_string()),
+            ],
+            limits.settings()
+        );
+        assert!(Limits {
+            io_weight: Some(0),
+            ..Limits::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
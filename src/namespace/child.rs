@@ -1,4 +1,5 @@
 use std::convert::TryInto;
+use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::marker::PhantomData;
@@ -14,6 +15,19 @@ use anyhow::Error;
 use anyhow::Context;
 use log::info;
 
+use super::cgroup::Cgroup;
+use super::cgroup::Usage;
+
+/// Sent by init with `Ready`, so an init left over from an older build can't be used.
+///
+/// The protocol is a hash of init's sources, from `build.rs`.
+pub const HANDSHAKE: &str = concat!(
+    "fappa ",
+    env!("CARGO_PKG_VERSION"),
+    " protocol ",
+    env!("FAPPA_INIT_PROTOCOL")
+);
+
 #[derive(Primitive, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CodeFrom {
     DebugOutput = 1,
@@ -22,6 +36,7 @@ pub enum CodeFrom {
     Ready = 4,
     Output = 5,
     SubExited = 6,
+    NetworkIsolated = 7,
 }
 
 #[derive(Primitive, Copy, Clone, Debug, PartialEq, Eq)]
@@ -30,6 +45,7 @@ pub enum CodeTo {
     RunAsRoot = 101,
     RunWithoutRoot = 102,
     Die = 103,
+    IsolateNetwork = 104,
 }
 
 pub struct Proto<S, R> {
@@ -41,14 +57,16 @@ pub struct Proto<S, R> {
 pub struct Child {
     pub proto: Proto<CodeTo, CodeFrom>,
     pub pid: nix::unistd::Pid,
+    pub cgroup: Option<Cgroup>,
 }
 
 #[derive(Debug, Clone)]
 pub enum FromChild {
     Debug(String),
-    Ready,
+    Ready(String),
     Output(Vec<u8>),
     SubExited(u8),
+    NetworkIsolated,
 }
 
 impl Child {
@@ -60,6 +78,14 @@ impl Child {
         }
     }
 
+    /// What the sandbox has used so far, if it's in a cgroup.
+    pub fn usage(&self) -> Result<Option<Usage>, Error> {
+        self.cgroup
+            .as_ref()
+            .map(|cgroup| cgroup.usage())
+            .transpose()
+    }
+
     pub fn msg(&mut self) -> Result<Option<FromChild>, Error> {
         let (code, data) = self.proto.read_msg()?;
         match code {
@@ -69,9 +95,12 @@ impl Child {
             }
             CodeFrom::ShutdownSuccess => Ok(None),
             CodeFrom::ShutdownError => Err(anyhow!(String::from_utf8(data)?)),
-            CodeFrom::Ready => Ok(Some(FromChild::Ready)),
+            CodeFrom::Ready => Ok(Some(FromChild::Ready(
+                String::from_utf8_lossy(&data).to_string(),
+            ))),
             CodeFrom::Output => Ok(Some(FromChild::Output(data))),
             CodeFrom::SubExited => Ok(Some(FromChild::SubExited(data[0]))),
+            CodeFrom::NetworkIsolated => Ok(Some(FromChild::NetworkIsolated)),
         }
     }
 }
@@ -129,11 +158,43 @@ impl<S: num_traits::ToPrimitive, R: num_traits::FromPrimitive> Proto<S, R> {
     }
 }
 
-pub fn await_ready(child: &mut Child) -> Result<(), Error> {
+/// Where the output and debug messages from the sandbox go.
+pub trait Sink {
+    fn output(&mut self, data: &[u8]) -> Result<(), Error>;
+    fn debug(&mut self, msg: &str) -> Result<(), Error>;
+}
+
+/// Output straight to our stdout, and debug messages to the log.
+pub struct Stdout;
+
+impl Sink for Stdout {
+    fn output(&mut self, data: &[u8]) -> Result<(), Error> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(data)?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn debug(&mut self, msg: &str) -> Result<(), Error> {
+        info!("child says: {}", msg);
+        Ok(())
+    }
+}
+
+pub fn await_ready(child: &mut Child, sink: &mut dyn Sink) -> Result<(), Error> {
     while let Some(event) = child.msg()? {
         match event {
-            FromChild::Ready => break,
-            FromChild::Debug(m) => info!("child says: {}", m),
+            FromChild::Ready(handshake) => {
+                ensure!(
+                    HANDSHAKE == handshake,
+                    "init is stale: it says {:?}, but we're {:?}; rebuild it with `just finit`",
+                    handshake,
+                    HANDSHAKE
+                );
+                break;
+            }
+            FromChild::Debug(m) => sink.debug(&m)?,
             _ => bail!("unexpected event: {:?}", event),
         }
     }
@@ -141,7 +202,13 @@ pub fn await_ready(child: &mut Child) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn execute(child: &mut Child, root: bool, cmd: &[u8]) -> Result<(), Error> {
+/// Run `cmd` in a shell inside the sandbox, returning its exit code.
+pub fn execute(
+    child: &mut Child,
+    root: bool,
+    cmd: &[u8],
+    sink: &mut dyn Sink,
+) -> Result<u8, Error> {
     let code = match root {
         true => CodeTo::RunAsRoot,
         false => CodeTo::RunWithoutRoot,
@@ -151,24 +218,37 @@ pub fn execute(child: &mut Child, root: bool, cmd: &[u8]) -> Result<(), Error> {
 
     while let Some(event) = child.msg()? {
         match event {
-            FromChild::Debug(m) => println!("child says: {}", m),
-            FromChild::Output(m) => println!("child printed: {:?}", String::from_utf8_lossy(&m)),
-            FromChild::SubExited(c) => {
-                println!("child exited: {}", c);
-                break;
-            }
+            FromChild::Debug(m) => sink.debug(&m)?,
+            FromChild::Output(m) => sink.output(&m)?,
+            FromChild::SubExited(c) => return Ok(c),
             _ => bail!("unexpected event: {:?}", event),
         }
     }
 
-    Ok(())
+    bail!("child shut down while running a command")
+}
+
+/// Move init, and so everything it runs from now on, into a network namespace of its own,
+/// with only `lo`, for a sandbox which started with the host's network.
+pub fn isolate_network(child: &mut Child, sink: &mut dyn Sink) -> Result<(), Error> {
+    child.proto.write_msg(CodeTo::IsolateNetwork, &[])?;
+
+    while let Some(event) = child.msg()? {
+        match event {
+            FromChild::Debug(m) => sink.debug(&m)?,
+            FromChild::NetworkIsolated => return Ok(()),
+            _ => bail!("unexpected event: {:?}", event),
+        }
+    }
+
+    bail!("child shut down while isolating the network")
 }
 
-pub fn shutdown(child: &mut Child) -> Result<(), Error> {
+pub fn shutdown(child: &mut Child, sink: &mut dyn Sink) -> Result<(), Error> {
     child.proto.write_msg(CodeTo::Die, &[])?;
     while let Some(event) = child.msg()? {
         match event {
-            FromChild::Debug(m) => info!("shutting down child says: {}", m),
+            FromChild::Debug(m) => sink.debug(&m)?,
             _ => bail!("unexpected event: {:?}", event),
         }
     }
@@ -0,0 +1,273 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+
+use super::cgroup::Limits;
+
+/// Everything about a sandbox which isn't the root it runs in.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub limits: Limits,
+    /// Build a minimal `/dev`, with a private devpts and `/dev/shm`, rather than just
+    /// binding `/dev/null` into the image's.
+    pub dev: bool,
+    /// Where to mount empty tmpfses, e.g. `/tmp`, so their contents never reach the root.
+    pub tmpfs: Vec<PathBuf>,
+    /// Host paths to make visible inside the sandbox, mounted in order, after everything else.
+    pub binds: Vec<Bind>,
+    pub network: Network,
+}
+
+/// What the sandbox can reach over the network.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Network {
+    /// A network namespace of its own, with only `lo` up.
+    Loopback,
+    /// The host's network, and its DNS servers, e.g. for `apt-get`. The sandbox can
+    /// still give this up later, with [`super::child::isolate_network`].
+    Host,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            limits: Limits::default(),
+            dev: true,
+            tmpfs: vec![PathBuf::from("/tmp")],
+            binds: Vec::new(),
+            network: Network::Loopback,
+        }
+    }
+}
+
+/// A host file or directory, bound into the sandbox.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bind {
+    pub host: PathBuf,
+    /// An absolute path, inside the sandbox.
+    pub inside: PathBuf,
+    pub writable: bool,
+}
+
+impl Bind {
+    pub fn read_only<H: Into<PathBuf>, I: Into<PathBuf>>(host: H, inside: I) -> Bind {
+        Bind {
+            host: host.into(),
+            inside: inside.into(),
+            writable: false,
+        }
+    }
+
+    /// `inside`, relative to the sandbox's root.
+    pub fn relative(&self) -> &Path {
+        self.inside
+            .strip_prefix("/")
+            .expect("validated to be absolute")
+    }
+}
+
+/// `host:inside`, optionally followed by `:ro` (the default) or `:rw`.
+impl FromStr for Bind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Bind, Error> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (host, inside, writable) = match parts.as_slice() {
+            [host, inside] => (host, inside, false),
+            [host, inside, "ro"] => (host, inside, false),
+            [host, inside, "rw"] => (host, inside, true),
+            _ => bail!(
+                "binds look like /host/path:/sandbox/path[:ro|:rw], not {:?}",
+                s
+            ),
+        };
+        Ok(Bind {
+            host: PathBuf::from(host),
+            inside: PathBuf::from(inside),
+            writable,
+        })
+    }
+}
+
+impl Config {
+    /// Check the mounts make sense in the image unpacked in `root`, before anything is mounted.
+    pub fn validate(&self, root: &Path) -> Result<(), Error> {
+        let init = init_inside(root)?;
+        let hides_init =
+            |path: &Path| Path::new("/bin/finit").starts_with(path) || init.starts_with(path);
+
+        let mut seen = HashSet::new();
+        for path in &self.tmpfs {
+            check_inside(path)?;
+            ensure!(!hides_init(path), "can't mount over our init: {:?}", path);
+            ensure!(seen.insert(path), "mounted on {:?} twice", path);
+        }
+
+        for bind in &self.binds {
+            ensure!(
+                bind.host.exists(),
+                "bind source doesn't exist: {:?}",
+                bind.host
+            );
+            check_inside(&bind.inside)?;
+            ensure!(
+                !hides_init(&bind.inside),
+                "can't bind over our init: {:?}",
+                bind.inside
+            );
+            ensure!(
+                seen.insert(&bind.inside),
+                "mounted on {:?} twice",
+                bind.inside
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Where our init is, inside the sandbox: `/bin/finit`, but with `/bin` resolved if it's
+/// a symlink, e.g. to `/usr/bin` in images with a merged `/usr`.
+fn init_inside(root: &Path) -> Result<PathBuf, Error> {
+    let mut bin = PathBuf::from("/");
+    match fs::read_link(root.join("bin")) {
+        Ok(target) => {
+            for component in target.components() {
+                match component {
+                    Component::Normal(part) => bin.push(part),
+                    Component::ParentDir => {
+                        bin.pop();
+                    }
+                    _ => (),
+                }
+            }
+        }
+        // not a symlink
+        Err(ref e) if io::ErrorKind::InvalidInput == e.kind() => bin.push("bin"),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => bin.push("bin"),
+        Err(e) => return Err(e).with_context(|| format_err!("reading {:?}", root.join("bin"))),
+    }
+    Ok(bin.join("finit"))
+}
+
+/// Mount points must be absolute, plain, paths, and not where the sandbox mounts things itself.
+fn check_inside(path: &Path) -> Result<(), Error> {
+    ensure!(
+        path.is_absolute(),
+        "mount points must be absolute: {:?}",
+        path
+    );
+    ensure!(
+        path.components().skip(1).all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false,
+        }),
+        "mount points must be plain paths: {:?}",
+        path
+    );
+
+    let top = path
+        .components()
+        .nth(1)
+        .ok_or_else(|| format_err!("can't mount over the sandbox's root: {:?}", path))?;
+    ensure!(
+        !["proc", "sys", "dev"]
+            .iter()
+            .any(|reserved| Component::Normal(reserved.as_ref()) == top),
+        "can't mount into /proc, /sys or /dev: {:?}",
+        path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use super::Bind;
+    use super::Config;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Bind::read_only("/home/me/src", "/build/src"),
+            "/home/me/src:/build/src".parse().unwrap()
+        );
+        let rw: Bind = "/var/cache/ccache:/ccache:rw".parse().unwrap();
+        assert!(rw.writable);
+        assert!("/a".parse::<Bind>().is_err());
+        assert!("/a:/b:rx".parse::<Bind>().is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let host = dir.path().to_str().unwrap();
+        let image = tempfile::TempDir::new().unwrap();
+        let root = image.path();
+        let config = |binds: &[&str]| Config {
+            binds: binds.iter().map(|b| b.parse().unwrap()).collect(),
+            ..Config::default()
+        };
+
+        assert!(config(&[&format!("{}:/build/src", host)])
+            .validate(root)
+            .is_ok());
+        assert!(config(&["/does/not/exist:/build/src"])
+            .validate(root)
+            .is_err());
+        assert!(config(&[&format!("{}:build/src", host)])
+            .validate(root)
+            .is_err());
+        assert!(config(&[&format!("{}:/build/../etc", host)])
+            .validate(root)
+            .is_err());
+        assert!(config(&[&format!("{}:/", host)]).validate(root).is_err());
+        assert!(config(&[&format!("{}:/proc/foo", host)])
+            .validate(root)
+            .is_err());
+        assert!(
+            config(&[&format!("{}:/a", host), &format!("{}:/a:rw", host)])
+                .validate(root)
+                .is_err()
+        );
+        assert!(config(&[&format!("{}:/tmp", host)]).validate(root).is_err());
+        assert!(config(&[&format!("{}:/tmp/cache", host)])
+            .validate(root)
+            .is_ok());
+
+        let mut dev = Config::default();
+        dev.tmpfs.push("/dev/shm".into());
+        assert!(dev.validate(root).is_err());
+
+        for init in &["/bin", "/bin/finit"] {
+            let bind = format!("{}:{}", host, init);
+            assert!(config(&[&bind]).validate(root).is_err());
+        }
+        let usr = format!("{}:/usr", host);
+        assert!(config(&[&usr]).validate(root).is_ok());
+
+        // with /usr merged, /bin/finit is really /usr/bin/finit
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        symlink("usr/bin", root.join("bin")).unwrap();
+        for init in &["/bin", "/bin/finit", "/usr", "/usr/bin", "/usr/bin/finit"] {
+            let bind = format!("{}:{}", host, init);
+            assert!(config(&[&bind]).validate(root).is_err());
+        }
+        let share = format!("{}:/usr/share", host);
+        assert!(config(&[&share]).validate(root).is_ok());
+        let mut tmpfs = Config::default();
+        tmpfs.tmpfs.push("/usr".into());
+        assert!(tmpfs.validate(root).is_err());
+    }
+}
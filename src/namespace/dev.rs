@@ -0,0 +1,131 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use nix::mount::mount;
+use nix::mount::MsFlags;
+
+/// A character device a build can expect to find in `/dev`.
+pub struct Device {
+    pub name: &'static str,
+    /// The host's node to bind, which may not be the one with this name.
+    pub host: &'static str,
+    pub major: u64,
+    pub minor: u64,
+}
+
+/// `random` is `urandom`, unless `real-random` is enabled, so builds never block on entropy.
+pub const DEVICES: [Device; 5] = [
+    Device {
+        name: "null",
+        host: "/dev/null",
+        major: 1,
+        minor: 3,
+    },
+    Device {
+        name: "zero",
+        host: "/dev/zero",
+        major: 1,
+        minor: 5,
+    },
+    Device {
+        name: "full",
+        host: "/dev/full",
+        major: 1,
+        minor: 7,
+    },
+    #[cfg(feature = "real-random")]
+    Device {
+        name: "random",
+        host: "/dev/random",
+        major: 1,
+        minor: 8,
+    },
+    #[cfg(not(feature = "real-random"))]
+    Device {
+        name: "random",
+        host: "/dev/urandom",
+        major: 1,
+        minor: 9,
+    },
+    Device {
+        name: "urandom",
+        host: "/dev/urandom",
+        major: 1,
+        minor: 9,
+    },
+];
+
+/// Only bound into sandboxes, which have a controlling terminal; `safenod` never made one.
+const TTY: Device = Device {
+    name: "tty",
+    host: "/dev/tty",
+    major: 5,
+    minor: 0,
+};
+
+/// Replace `dev`, inside the root which is our working directory, with a tmpfs holding
+/// just the host's `DEVICES` and `tty`, a private devpts, and a tmpfs `/dev/shm`.
+pub fn populate() -> Result<(), Error> {
+    let unset: Option<&str> = None;
+    let dev = Path::new("dev");
+    fs::create_dir_all(dev)?;
+
+    mount(
+        Some("tmpfs"),
+        dev,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("mode=0755"),
+    )
+    .with_context(|| anyhow!("mount -t tmpfs dev"))?;
+
+    for device in DEVICES.iter().chain(Some(&TTY)) {
+        let node = dev.join(device.name);
+        drop(fs::File::create(&node)?);
+        mount(Some(device.host), &node, unset, MsFlags::MS_BIND, unset)
+            .with_context(|| format_err!("mount --bind {} {:?}", device.host, node))?;
+    }
+
+    fs::create_dir(dev.join("pts"))?;
+    mount(
+        Some("devpts"),
+        &dev.join("pts"),
+        Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("newinstance,ptmxmode=0666,mode=0620,gid=5"),
+    )
+    .with_context(|| anyhow!("mount -t devpts dev/pts"))?;
+    symlink("pts/ptmx", dev.join("ptmx"))?;
+
+    fs::create_dir(dev.join("shm"))?;
+    tmpfs(&dev.join("shm"))?;
+
+    for (name, target) in &[
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ] {
+        symlink(target, dev.join(name))?;
+    }
+
+    Ok(())
+}
+
+/// Mount a world-writable, sticky, tmpfs at `path`, like `/tmp`.
+pub fn tmpfs(path: &Path) -> Result<(), Error> {
+    mount(
+        Some("tmpfs"),
+        path,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("mode=1777"),
+    )
+    .with_context(|| format_err!("mount -t tmpfs {:?}", path))?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use nix::errno::Errno;
+
+/// Where `systemd-resolved` keeps the real upstream servers, when the host's
+/// `/etc/resolv.conf` only points at its stub listener.
+const RESOLVED_UPSTREAM: &str = "/run/systemd/resolve/resolv.conf";
+
+/// `struct ifreq`, with only the flags member of the union, padded to the full size.
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// Bring up `lo`, the only interface in a new network namespace, which starts down.
+pub fn loopback_up() -> Result<(), Error> {
+    let sock = Errno::result(unsafe {
+        libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0)
+    })
+    .with_context(|| anyhow!("opening socket to configure lo"))?;
+    // closes it on the way out
+    let _sock = unsafe { fs::File::from_raw_fd(sock) };
+
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: 0,
+        _pad: [0; 22],
+    };
+    req.name[..2].copy_from_slice(b"lo");
+
+    Errno::result(unsafe { libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req) })
+        .with_context(|| anyhow!("reading flags of lo"))?;
+    req.flags |= libc::IFF_UP as libc::c_short;
+    Errno::result(unsafe { libc::ioctl(sock, libc::SIOCSIFFLAGS, &req) })
+        .with_context(|| anyhow!("ip link set lo up"))?;
+    Ok(())
+}
+
+/// A `resolv.conf` for a sandbox sharing our network, from the host's.
+///
+/// If the host only uses `systemd-resolved`'s stub listener, its upstream servers are
+/// used instead, so this still works if that isn't running when the sandbox is.
+pub fn host_resolv_conf() -> Result<String, Error> {
+    let etc = fs::read_to_string("/etc/resolv.conf")
+        .with_context(|| anyhow!("reading host's /etc/resolv.conf"))?;
+    let conf = resolv_conf(&etc);
+    if !only_stub(&conf) || !Path::new(RESOLVED_UPSTREAM).is_file() {
+        return conf;
+    }
+    resolv_conf(&fs::read_to_string(RESOLVED_UPSTREAM)?)
+        .with_context(|| format_err!("reading {}", RESOLVED_UPSTREAM))
+}
+
+/// Just the settings from a `resolv.conf`, without comments, requiring a nameserver.
+fn resolv_conf(host: &str) -> Result<String, Error> {
+    let mut ret = String::new();
+    for line in host.lines() {
+        let line = line.trim();
+        let keyword = line.split_whitespace().next().unwrap_or("");
+        if ["nameserver", "search", "domain", "options"].contains(&keyword) {
+            ret.push_str(line);
+            ret.push('\n');
+        }
+    }
+    ensure!(
+        nameservers(&ret).next().is_some(),
+        "no nameservers in the host's resolv.conf"
+    );
+    Ok(ret)
+}
+
+fn only_stub(conf: &Result<String, Error>) -> bool {
+    match conf {
+        Ok(conf) => nameservers(conf).all(|server| "127.0.0.53" == server),
+        Err(_) => false,
+    }
+}
+
+fn nameservers(conf: &str) -> impl Iterator<Item = &str> {
+    conf.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("nameserver") => words.next(),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolv_conf;
+
+    #[test]
+    fn resolv() {
+        assert_eq!(
+            "nameserver 10.0.0.1\nnameserver 2001:db8::1\nsearch example.com\noptions edns0\n",
+            resolv_conf(concat!(
+                "# Generated by NetworkManager\n",
+                "nameserver 10.0.0.1\n",
+                "  nameserver 2001:db8::1\n",
+                "\n",
+                "search example.com\n",
+                "; a comment\n",
+                "options edns0\n",
+            ))
+            .unwrap()
+        );
+        assert!(resolv_conf("# nothing\nsearch example.com\n").is_err());
+        assert!(super::only_stub(&resolv_conf("nameserver 127.0.0.53\n")));
+        assert!(!super::only_stub(&resolv_conf(
+            "nameserver 127.0.0.53\nnameserver 10.0.0.1\n"
+        )));
+    }
+}
@@ -0,0 +1,427 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+
+/// One entry from a quilt `series` file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Patch {
+    pub name: String,
+    pub strip: usize,
+    pub files: Vec<FilePatch>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilePatch {
+    /// `None` if the file is being created
+    pub old: Option<String>,
+    /// `None` if the file is being deleted
+    pub new: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hunk {
+    pub header: String,
+    pub old_start: usize,
+    /// Lines, including their newline (if they had one), which must be present.
+    pub old: Vec<String>,
+    /// Lines to replace them with.
+    pub new: Vec<String>,
+}
+
+/// Read `dir/series`, and every patch it mentions.
+pub fn load_series<P: AsRef<Path>>(dir: P) -> Result<Vec<Patch>, Error> {
+    let dir = dir.as_ref();
+    let series = dir.join("series");
+    let series =
+        fs::read_to_string(&series).with_context(|| format_err!("reading {:?}", series))?;
+
+    let mut ret = Vec::new();
+    for line in series.lines() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let mut strip = 1;
+        for option in words {
+            strip = match option {
+                "-p0" => 0,
+                "-p1" => 1,
+                other => bail!("unsupported option for {:?}: {:?}", name, other),
+            };
+        }
+
+        let path = dir.join(name);
+        let text = fs::read_to_string(&path).with_context(|| format_err!("reading {:?}", path))?;
+        let files = parse(&text).with_context(|| format_err!("parsing patch {:?}", name))?;
+        ret.push(Patch {
+            name: name.to_string(),
+            strip,
+            files,
+        });
+    }
+
+    Ok(ret)
+}
+
+/// Apply every patch in `dir/series`, in order, to the tree at `dest`.
+pub fn apply_series<P: AsRef<Path>, D: AsRef<Path>>(dir: P, dest: D) -> Result<(), Error> {
+    for patch in load_series(dir)? {
+        apply(&patch, dest.as_ref()).with_context(|| format_err!("applying {}", patch.name))?;
+    }
+    Ok(())
+}
+
+pub fn apply(patch: &Patch, dest: &Path) -> Result<(), Error> {
+    for file in &patch.files {
+        let old = file
+            .old
+            .as_ref()
+            .map(|p| target(dest, p, patch.strip))
+            .transpose()?;
+        let new = file
+            .new
+            .as_ref()
+            .map(|p| target(dest, p, patch.strip))
+            .transpose()?;
+
+        let mut lines = match &old {
+            Some(path) => split_lines(
+                &fs::read_to_string(path).with_context(|| format_err!("reading {:?}", path))?,
+            ),
+            None => Vec::new(),
+        };
+
+        let mut offset: isize = 0;
+        for (i, hunk) in file.hunks.iter().enumerate() {
+            // an empty old range, e.g. `-5,0` from `diff -U0`, inserts after that line
+            let index = match hunk.old.is_empty() {
+                true => hunk.old_start,
+                false => hunk.old_start.max(1) - 1,
+            };
+            let expected = index as isize + offset;
+            let found = find(&lines, &hunk.old, expected).ok_or_else(|| {
+                anyhow!(
+                    "hunk #{} FAILED at {} in {:?}:\n{}",
+                    i + 1,
+                    hunk.header,
+                    old.as_ref().or(new.as_ref()).expect("one side exists"),
+                    hunk.old.concat()
+                )
+            })?;
+            lines.splice(found..found + hunk.old.len(), hunk.new.iter().cloned());
+            offset += found as isize - expected + hunk.new.len() as isize - hunk.old.len() as isize;
+        }
+
+        match (old, new) {
+            (Some(old), None) => {
+                ensure!(lines.is_empty(), "deleted file {:?} has content left", old);
+                fs::remove_file(&old)?;
+            }
+            (old, Some(new)) => {
+                if let Some(old) = old {
+                    if old != new {
+                        fs::remove_file(&old)?;
+                    }
+                }
+                if let Some(parent) = new.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&new, lines.concat())?;
+            }
+            (None, None) => bail!("patch neither creates nor modifies a file"),
+        }
+    }
+
+    Ok(())
+}
+
+fn target(dest: &Path, path: &str, strip: usize) -> Result<PathBuf, Error> {
+    let stripped: Vec<&str> = path
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .skip(strip)
+        .collect();
+    ensure!(
+        !stripped.is_empty() && !stripped.iter().any(|&p| ".." == p),
+        "invalid path in patch: {:?}",
+        path
+    );
+    Ok(dest.join(stripped.join("/")))
+}
+
+/// Find `needle` in `lines`, as close to `expected` as possible.
+fn find(lines: &[String], needle: &[String], expected: isize) -> Option<usize> {
+    let fits = |pos: isize| {
+        pos >= 0
+            && pos as usize + needle.len() <= lines.len()
+            && lines[pos as usize..pos as usize + needle.len()] == *needle
+    };
+
+    for delta in 0..=lines.len() as isize {
+        if fits(expected - delta) {
+            return Some((expected - delta) as usize);
+        }
+        if fits(expected + delta) {
+            return Some((expected + delta) as usize);
+        }
+    }
+
+    None
+}
+
+fn split_lines(text: &str) -> Vec<String> {
+    text.split_inclusive('\n').map(|l| l.to_string()).collect()
+}
+
+/// Parse a unified diff, as produced by `diff -u`, `git diff` or quilt.
+pub fn parse(text: &str) -> Result<Vec<FilePatch>, Error> {
+    let lines = split_lines(text);
+    let mut ret = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        if !(lines[i].starts_with("--- ")
+            && i + 1 < lines.len()
+            && lines[i + 1].starts_with("+++ "))
+        {
+            i += 1;
+            continue;
+        }
+
+        let old = header_path(&lines[i][4..]);
+        let new = header_path(&lines[i + 1][4..]);
+        i += 2;
+
+        let mut hunks = Vec::new();
+        while i < lines.len() && lines[i].starts_with("@@ ") {
+            let (hunk, used) = parse_hunk(&lines[i..])
+                .with_context(|| format_err!("parsing hunk {:?}", lines[i].trim_end()))?;
+            hunks.push(hunk);
+            i += used;
+        }
+
+        ensure!(
+            !hunks.is_empty(),
+            "no hunks for {:?}",
+            old.as_ref().or(new.as_ref())
+        );
+        ret.push(FilePatch { old, new, hunks });
+    }
+
+    ensure!(!ret.is_empty(), "no changes found");
+
+    Ok(ret)
+}
+
+fn header_path(line: &str) -> Option<String> {
+    let path = line
+        .trim_end_matches('\n')
+        .split('\t')
+        .next()
+        .unwrap_or("")
+        .trim();
+    if "/dev/null" == path {
+        None
+    } else {
+        Some(path.to_string())
+    }
+}
+
+fn parse_hunk(lines: &[String]) -> Result<(Hunk, usize), Error> {
+    let header = lines[0].trim_end().to_string();
+    let ranges = header
+        .trim_start_matches("@@ ")
+        .split(" @@")
+        .next()
+        .ok_or_else(|| anyhow!("invalid header"))?;
+    let mut ranges = ranges.split(' ');
+    let (old_start, mut old_len) = range(ranges.next(), '-')?;
+    let (_, mut new_len) = range(ranges.next(), '+')?;
+
+    let mut hunk = Hunk {
+        header,
+        old_start,
+        old: Vec::new(),
+        new: Vec::new(),
+    };
+
+    let mut used = 1;
+    let mut last: Option<char> = None;
+
+    while old_len > 0 || new_len > 0 || lines.get(used).map(|l| l.starts_with('\\')) == Some(true) {
+        let line = lines.get(used).ok_or_else(|| anyhow!("hunk truncated"))?;
+        used += 1;
+
+        let kind = line.chars().next().expect("split lines are never empty");
+        let content = || line[1..].to_string();
+
+        match kind {
+            // some tools drop the space on an empty context line
+            ' ' | '\n' => {
+                let content = if '\n' == kind {
+                    "\n".to_string()
+                } else {
+                    content()
+                };
+                ensure!(old_len > 0 && new_len > 0, "too many context lines");
+                old_len -= 1;
+                new_len -= 1;
+                hunk.old.push(content.clone());
+                hunk.new.push(content);
+            }
+            '-' => {
+                ensure!(old_len > 0, "too many removed lines");
+                old_len -= 1;
+                hunk.old.push(content());
+            }
+            '+' => {
+                ensure!(new_len > 0, "too many added lines");
+                new_len -= 1;
+                hunk.new.push(content());
+            }
+            '\\' => {
+                // "\ No newline at end of file" applies to the previous line
+                let strip = |v: &mut Vec<String>| {
+                    if let Some(l) = v.last_mut() {
+                        if l.ends_with('\n') {
+                            l.pop();
+                        }
+                    }
+                };
+                match last {
+                    Some('-') => strip(&mut hunk.old),
+                    Some('+') => strip(&mut hunk.new),
+                    Some(' ') => {
+                        strip(&mut hunk.old);
+                        strip(&mut hunk.new);
+                    }
+                    _ => bail!("misplaced 'no newline' marker"),
+                }
+                continue;
+            }
+            other => bail!("unexpected line type {:?}", other),
+        }
+
+        last = Some(if '\n' == kind { ' ' } else { kind });
+    }
+
+    Ok((hunk, used))
+}
+
+/// `-12,3` or `+12`
+fn range(spec: Option<&str>, prefix: char) -> Result<(usize, usize), Error> {
+    let spec = spec
+        .filter(|s| s.starts_with(prefix))
+        .ok_or_else(|| anyhow!("expected a {} range", prefix))?;
+    let spec = &spec[1..];
+    Ok(match spec.find(',') {
+        Some(comma) => (spec[..comma].parse()?, spec[comma + 1..].parse()?),
+        None => (spec.parse()?, 1),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    const PATCH: &str = r#"Description: fix the greeting
+--- a/hello.c
++++ b/hello.c
+@@ -1,4 +1,4 @@
+ #include <stdio.h>
+ int main() {
+-    printf("hello\n");
++    printf("hello, world\n");
+ }
+--- /dev/null
++++ b/NEWS
+@@ -0,0 +1 @@
++patched
+\ No newline at end of file
+"#;
+
+    #[test]
+    fn parse() {
+        let files = super::parse(PATCH).unwrap();
+        assert_eq!(2, files.len());
+        assert_eq!(Some("a/hello.c".to_string()), files[0].old);
+        assert_eq!(4, files[0].hunks[0].old.len());
+        assert_eq!(None, files[1].old);
+        assert_eq!(vec!["patched".to_string()], files[1].hunks[0].new);
+    }
+
+    #[test]
+    fn apply_with_offset() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let series = dir.path().join("patches");
+        fs::create_dir(&series).unwrap();
+        fs::write(series.join("series"), "# local fixes\nhello.patch\n").unwrap();
+        fs::write(series.join("hello.patch"), PATCH).unwrap();
+
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(
+            src.join("hello.c"),
+            "// new header line\n#include <stdio.h>\nint main() {\n    printf(\"hello\\n\");\n}\n",
+        )
+        .unwrap();
+
+        super::apply_series(&series, &src).unwrap();
+
+        assert_eq!(
+            "// new header line\n#include <stdio.h>\nint main() {\n    printf(\"hello, world\\n\");\n}\n",
+            fs::read_to_string(src.join("hello.c")).unwrap()
+        );
+        assert_eq!("patched", fs::read_to_string(src.join("NEWS")).unwrap());
+    }
+
+    #[test]
+    fn apply_without_context() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("list"), "a\nb\nc\nd\n").unwrap();
+
+        // diff -U0
+        let patch = super::Patch {
+            name: "list.patch".to_string(),
+            strip: 1,
+            files: super::parse(
+                "--- a/list\n+++ b/list\n@@ -1,0 +2,2 @@\n+x\n+y\n@@ -3 +5 @@\n-c\n+z\n@@ -4,0 +7 @@\n+e\n",
+            )
+            .unwrap(),
+        };
+        super::apply(&patch, dir.path()).unwrap();
+
+        assert_eq!(
+            "a\nx\ny\nb\nz\nd\ne\n",
+            fs::read_to_string(dir.path().join("list")).unwrap()
+        );
+    }
+
+    #[test]
+    fn conflict() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("hello.c"), "int main() {}\n").unwrap();
+
+        let patch = super::Patch {
+            name: "hello.patch".to_string(),
+            strip: 1,
+            files: super::parse(PATCH).unwrap(),
+        };
+        let err = format!("{:#}", super::apply(&patch, dir.path()).unwrap_err());
+        assert!(err.contains("hunk #1 FAILED at @@ -1,4 +1,4 @@"), "{}", err);
+    }
+}
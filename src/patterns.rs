@@ -0,0 +1,138 @@
+use anyhow::ensure;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use globset::GlobBuilder;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+
+/// A list of globs, matched against absolute paths inside the build root.
+///
+/// `*` and `?` never match a `/`, `**` matches any number of directories, `[a-z]` and
+/// `[!a-z]` are character classes. A leading `!` negates a pattern: as in `.gitignore`,
+/// the last pattern which matches a path decides whether the path is matched.
+#[derive(Clone, Debug)]
+pub struct Patterns {
+    sources: Vec<String>,
+    negated: Vec<bool>,
+    set: GlobSet,
+}
+
+impl Patterns {
+    pub fn new(sources: Vec<String>) -> Result<Patterns, Error> {
+        let mut builder = GlobSetBuilder::new();
+        let mut negated = Vec::with_capacity(sources.len());
+
+        for source in &sources {
+            let (negate, pattern) = match source.starts_with('!') {
+                true => (true, &source[1..]),
+                false => (false, source.as_str()),
+            };
+
+            ensure!(
+                pattern.starts_with('/'),
+                "patterns must be absolute: {:?}",
+                source
+            );
+
+            builder.add(
+                GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .backslash_escape(true)
+                    .build()
+                    .with_context(|| format_err!("invalid pattern: {:?}", source))?,
+            );
+            negated.push(negate);
+        }
+
+        Ok(Patterns {
+            set: builder.build()?,
+            sources,
+            negated,
+        })
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        self.set
+            .matches(path)
+            .into_iter()
+            .max()
+            .map(|last| !self.negated[last])
+            .unwrap_or(false)
+    }
+
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Patterns::new(Vec::new()).expect("empty patterns are valid")
+    }
+}
+
+impl PartialEq for Patterns {
+    fn eq(&self, other: &Self) -> bool {
+        self.sources == other.sources
+    }
+}
+
+impl Eq for Patterns {}
+
+#[cfg(test)]
+mod tests {
+    use super::Patterns;
+
+    fn patterns(sources: &[&str]) -> Patterns {
+        Patterns::new(sources.iter().map(|s| s.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn recursive() {
+        let p = patterns(&["/usr/local/**"]);
+        assert!(p.is_match("/usr/local/lib/libfoo.so"));
+        assert!(p.is_match("/usr/local/include"));
+        assert!(!p.is_match("/usr/localise"));
+        assert!(!p.is_match("/usr/lib/libfoo.so"));
+    }
+
+    #[test]
+    fn wildcards() {
+        let p = patterns(&["/usr/lib/*.so.?", "/usr/**/pkgconfig/*.pc", "/etc/[a-c]*"]);
+        assert!(p.is_match("/usr/lib/libfoo.so.1"));
+        assert!(!p.is_match("/usr/lib/x86_64-linux-gnu/libfoo.so.1"));
+        assert!(!p.is_match("/usr/lib/libfoo.so.12"));
+        assert!(p.is_match("/usr/lib/pkgconfig/foo.pc"));
+        assert!(p.is_match("/usr/local/lib/x86_64-linux-gnu/pkgconfig/foo.pc"));
+        assert!(p.is_match("/etc/bar"));
+        assert!(!p.is_match("/etc/dar"));
+    }
+
+    #[test]
+    fn negation() {
+        let p = patterns(&[
+            "/usr/local/**",
+            "!/usr/local/**/*.la",
+            "/usr/local/lib/keep.la",
+        ]);
+        assert!(p.is_match("/usr/local/lib/libfoo.so"));
+        assert!(!p.is_match("/usr/local/lib/libfoo.la"));
+        assert!(p.is_match("/usr/local/lib/keep.la"));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(!Patterns::default().is_match("/anything"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Patterns::new(vec!["/usr/[local".to_string()]).is_err());
+        assert!(Patterns::new(vec!["usr/local/**".to_string()]).is_err());
+    }
+}
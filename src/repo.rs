@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use log::info;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tempfile_fast::Sponge;
+
+use crate::deb;
+
+/// Where the stanzas for unchanged `.deb`s are remembered, inside the repository.
+const INDEX: &str = ".fappa-index.json";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Index {
+    debs: BTreeMap<String, Indexed>,
+}
+
+/// What's checked to tell if a `.deb` has changed: its times are only precise to the second,
+/// but packages are always replaced with a rename, so a rebuilt one has a new inode.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Indexed {
+    size: u64,
+    mtime: i64,
+    #[serde(default)]
+    mtime_nsec: i64,
+    #[serde(default)]
+    inode: u64,
+    stanza: String,
+}
+
+/// Regenerate the indexes of the flat repository in `dir`, which contains the `.deb`s
+/// built for `codename`, e.g. for `deb [trusted=yes] file:/path/to/dir ./`.
+///
+/// Only `.deb`s which have changed since the last update are read. If `sign_key` is set,
+/// `gpg` is used to write an `InRelease` file, too; otherwise, any old one is removed.
+pub fn update(dir: &Path, codename: &str, sign_key: Option<&str>) -> Result<(), Error> {
+    let index_path = dir.join(INDEX);
+    let old: Index = match fs::read(&index_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .with_context(|| format_err!("reading repository index {:?}", index_path))?,
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => Index::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut index = Index::default();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().to_str() {
+            Some(name) if name.ends_with(".deb") => name.to_string(),
+            _ => continue,
+        };
+        let meta = entry.metadata()?;
+        let unchanged = |indexed: &&Indexed| {
+            indexed.size == meta.len()
+                && indexed.mtime == meta.mtime()
+                && indexed.mtime_nsec == meta.mtime_nsec()
+                && indexed.inode == meta.ino()
+        };
+        let indexed = match old.debs.get(&name).filter(unchanged) {
+            Some(indexed) => indexed.clone(),
+            None => {
+                let content = fs::read(entry.path())?;
+                Indexed {
+                    size: meta.len(),
+                    mtime: meta.mtime(),
+                    mtime_nsec: meta.mtime_nsec(),
+                    inode: meta.ino(),
+                    stanza: stanza(&name, &content)
+                        .with_context(|| format_err!("indexing {:?}", name))?,
+                }
+            }
+        };
+        index.debs.insert(name, indexed);
+    }
+
+    let packages = index
+        .debs
+        .values()
+        .map(|indexed| indexed.stanza.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    gz.write_all(packages.as_bytes())?;
+    let packages_gz = gz.finish()?;
+
+    let release = release(
+        codename,
+        &[
+            ("Packages", packages.as_bytes()),
+            ("Packages.gz", &packages_gz),
+        ],
+    );
+
+    write(dir.join("Packages"), packages.as_bytes())?;
+    write(dir.join("Packages.gz"), &packages_gz)?;
+    write(dir.join("Release"), release.as_bytes())?;
+
+    match sign_key {
+        Some(key) => sign(dir, key)?,
+        // apt prefers it, so one left from a signed run would hide the new Release
+        None => match fs::remove_file(dir.join("InRelease")) {
+            Err(ref e) if io::ErrorKind::NotFound == e.kind() => (),
+            other => other?,
+        },
+    }
+
+    let mut sponge = Sponge::new_for(&index_path)?;
+    serde_json::to_writer_pretty(&mut sponge, &index)?;
+    sponge.commit()?;
+
+    info!(
+        "{}: indexed {} packages in {:?}",
+        codename,
+        index.debs.len(),
+        dir
+    );
+    Ok(())
+}
+
+/// The `Packages` entry for a `.deb`: its control file, plus where to find it.
+fn stanza(name: &str, content: &[u8]) -> Result<String, Error> {
+    let members = deb::members(content)?;
+    let control_tar = members
+        .iter()
+        .find(|(member, _)| "control.tar.xz" == member)
+        .ok_or_else(|| anyhow!("no control.tar.xz"))?;
+
+    let mut control = None;
+    let mut tar = tar::Archive::new(xz2::read::XzDecoder::new(control_tar.1.as_slice()));
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        if "control" == path.trim_start_matches("./") {
+            let mut text = String::new();
+            entry.read_to_string(&mut text)?;
+            control = Some(text);
+            break;
+        }
+    }
+    let control = control.ok_or_else(|| anyhow!("no control file"))?;
+
+    let mut ret = control.trim_end().to_string();
+    ret.push('\n');
+    ret.push_str(&format!("Filename: ./{}\n", name));
+    ret.push_str(&format!("Size: {}\n", content.len()));
+    ret.push_str(&format!("MD5sum: {:x}\n", md5::compute(content)));
+    ret.push_str(&format!("SHA256: {:x}\n", Sha256::digest(content)));
+    Ok(ret)
+}
+
+fn release(codename: &str, files: &[(&str, &[u8])]) -> String {
+    let mut ret = String::new();
+    ret.push_str("Origin: fappa\n");
+    ret.push_str("Label: fappa\n");
+    ret.push_str(&format!("Suite: {}\n", codename));
+    ret.push_str(&format!("Codename: {}\n", codename));
+    ret.push_str(&format!(
+        "Date: {}\n",
+        httpdate::fmt_http_date(SystemTime::now())
+    ));
+    ret.push_str("Architectures: amd64\n");
+    ret.push_str("MD5Sum:\n");
+    for (name, content) in files {
+        ret.push_str(&format!(
+            " {:x} {} {}\n",
+            md5::compute(content),
+            content.len(),
+            name
+        ));
+    }
+    ret.push_str("SHA256:\n");
+    for (name, content) in files {
+        ret.push_str(&format!(
+            " {:x} {} {}\n",
+            Sha256::digest(content),
+            content.len(),
+            name
+        ));
+    }
+    ret
+}
+
+fn sign(dir: &Path, key: &str) -> Result<(), Error> {
+    let in_release = dir.join("InRelease");
+    let temp = dir.join("InRelease.tmp");
+    let status = process::Command::new("gpg")
+        .args(&[
+            "--batch",
+            "--yes",
+            "--local-user",
+            key,
+            "--clearsign",
+            "--output",
+        ])
+        .arg(&temp)
+        .arg(dir.join("Release"))
+        .status()
+        .with_context(|| anyhow!("running gpg"))?;
+    ensure!(
+        status.success(),
+        "signing Release with gpg failed: {}",
+        status
+    );
+    fs::rename(&temp, &in_release)?;
+    Ok(())
+}
+
+fn write<P: AsRef<Path>>(path: P, content: &[u8]) -> Result<(), Error> {
+    let mut sponge = Sponge::new_for(path)?;
+    sponge.write_all(content)?;
+    sponge.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    use crate::deb::tests::TestPackage;
+
+    fn write_deb(root: &TestPackage, dir: &Path, package: &str, version: &str) {
+        let out = root.deb(package, version, None);
+        fs::write(dir.join(format!("{}_{}_amd64.deb", package, version)), out).unwrap();
+    }
+
+    #[test]
+    fn update() {
+        let root = TestPackage::new("foo");
+
+        let repo = tempfile::TempDir::new().unwrap();
+        write_deb(&root, repo.path(), "foo", "1.0~bionic");
+        super::update(repo.path(), "bionic", None).unwrap();
+
+        let packages = fs::read_to_string(repo.path().join("Packages")).unwrap();
+        assert!(packages.starts_with("Package: foo\nVersion: 1.0~bionic\n"));
+        assert!(packages.contains("\nDepends: libc6\n"));
+        assert!(packages.contains("\nFilename: ./foo_1.0~bionic_amd64.deb\n"));
+        assert!(packages.contains("\nSHA256: "));
+
+        write_deb(&root, repo.path(), "bar", "2~bionic");
+        super::update(repo.path(), "bionic", None).unwrap();
+
+        let packages = fs::read_to_string(repo.path().join("Packages")).unwrap();
+        let stanzas: Vec<&str> = packages.split("\n\n").collect();
+        assert_eq!(2, stanzas.len());
+        assert!(stanzas[0].starts_with("Package: bar\n"));
+        assert!(stanzas[1].starts_with("Package: foo\n"));
+
+        let mut unzipped = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(repo.path().join("Packages.gz")).unwrap())
+            .read_to_string(&mut unzipped)
+            .unwrap();
+        assert_eq!(packages, unzipped);
+
+        let release = fs::read_to_string(repo.path().join("Release")).unwrap();
+        assert!(release.contains("\nCodename: bionic\n"));
+        assert!(release.contains(&format!(" {} Packages\n", packages.len())));
+        assert!(release.contains("\nSHA256:\n"));
+        assert!(!repo.path().join("InRelease").exists());
+
+        fs::write(repo.path().join("InRelease"), "signed, but out of date").unwrap();
+        super::update(repo.path(), "bionic", None).unwrap();
+        assert!(!repo.path().join("InRelease").exists());
+    }
+
+    #[test]
+    fn rebuilt() {
+        use nix::sys::stat::utimes;
+        use nix::sys::time::TimeVal;
+        use nix::sys::time::TimeValLike;
+
+        let root = TestPackage::new("one");
+        let repo = tempfile::TempDir::new().unwrap();
+        let deb = repo.path().join("foo_1.0~bionic_amd64.deb");
+        // as a build does, and then as if it had finished within the same second
+        let replace = |content: Vec<u8>| {
+            let new = repo.path().join("new");
+            fs::write(&new, content).unwrap();
+            let time = TimeVal::seconds(1_500_000_000);
+            utimes(&new, &time, &time).unwrap();
+            fs::rename(&new, &deb).unwrap();
+        };
+
+        replace(root.deb("foo", "1.0~bionic", None));
+        super::update(repo.path(), "bionic", None).unwrap();
+        let first = fs::read_to_string(repo.path().join("Packages")).unwrap();
+
+        root.set_script("two");
+        let rebuilt = root.deb("foo", "1.0~bionic", None);
+        assert_eq!(fs::metadata(&deb).unwrap().len(), rebuilt.len() as u64);
+        replace(rebuilt);
+        super::update(repo.path(), "bionic", None).unwrap();
+        assert_ne!(
+            first,
+            fs::read_to_string(repo.path().join("Packages")).unwrap()
+        );
+    }
+}
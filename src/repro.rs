@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+
+use crate::deb;
+
+/// The differences between two sets of `.deb`s, matched up by file name.
+pub fn compare_runs(first: &[PathBuf], second: &[PathBuf]) -> Result<Vec<String>, Error> {
+    let by_name = |debs: &[PathBuf]| -> BTreeMap<String, PathBuf> {
+        debs.iter()
+            .map(|path| {
+                let name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                (name, path.to_path_buf())
+            })
+            .collect()
+    };
+    let first = by_name(first);
+    let second = by_name(second);
+
+    let mut ret = Vec::new();
+    for (name, path) in &first {
+        let other = match second.get(name) {
+            Some(other) => other,
+            None => {
+                ret.push(format!("{}: only built the first time", name));
+                continue;
+            }
+        };
+        let a = fs::read(path)?;
+        let b = fs::read(other)?;
+        let differences =
+            compare_debs(&a, &b).with_context(|| format_err!("comparing {}", name))?;
+        for difference in differences {
+            ret.push(format!("{}: {}", name, difference));
+        }
+    }
+    for name in second.keys().filter(|name| !first.contains_key(*name)) {
+        ret.push(format!("{}: only built the second time", name));
+    }
+
+    Ok(ret)
+}
+
+/// What differs between two `.deb`s, down to the tarball entries.
+pub fn compare_debs(a: &[u8], b: &[u8]) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
+    if a == b {
+        return Ok(ret);
+    }
+
+    let a = deb::members(a)?;
+    let b = deb::members(b)?;
+    let names = |members: &[(String, Vec<u8>)]| -> Vec<String> {
+        members.iter().map(|(name, _)| name.to_string()).collect()
+    };
+    if names(&a) != names(&b) {
+        ret.push(format!(
+            "members differ: {:?} vs {:?}",
+            names(&a),
+            names(&b)
+        ));
+        return Ok(ret);
+    }
+
+    for ((name, a), (_, b)) in a.iter().zip(&b) {
+        if a == b {
+            continue;
+        }
+        if name.ends_with(".tar.xz") {
+            let entries = compare_tars(a, b)?;
+            if entries.is_empty() {
+                ret.push(format!(
+                    "{}: same contents, but compressed differently",
+                    name
+                ));
+            }
+            ret.extend(entries.into_iter().map(|e| format!("{}: {}", name, e)));
+        } else {
+            ret.push(format!("{}: {}", name, first_difference(a, b)));
+        }
+    }
+
+    Ok(ret)
+}
+
+/// A tarball entry's header fields, and content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    kind: u8,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    mtime: u64,
+    link: Option<PathBuf>,
+    content: Vec<u8>,
+}
+
+fn entries(xz: &[u8]) -> Result<Vec<(String, Entry)>, Error> {
+    let mut tar = tar::Archive::new(xz2::read::XzDecoder::new(xz));
+    let mut ret = Vec::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mut value = Entry {
+            kind: header.entry_type().as_byte(),
+            mode: header.mode()?,
+            uid: header.uid()?,
+            gid: header.gid()?,
+            mtime: header.mtime()?,
+            link: entry.link_name()?.map(|l| l.to_path_buf()),
+            content: Vec::new(),
+        };
+        entry.read_to_end(&mut value.content)?;
+        ret.push((path, value));
+    }
+    Ok(ret)
+}
+
+fn compare_tars(a: &[u8], b: &[u8]) -> Result<Vec<String>, Error> {
+    let a = entries(a)?;
+    let b = entries(b)?;
+    let mut ret = Vec::new();
+
+    let order = |entries: &[(String, Entry)]| -> Vec<String> {
+        entries.iter().map(|(path, _)| path.to_string()).collect()
+    };
+    let mut sorted_a = order(&a);
+    let mut sorted_b = order(&b);
+    sorted_a.sort();
+    sorted_b.sort();
+    if sorted_a == sorted_b && order(&a) != order(&b) {
+        ret.push("entries are in a different order".to_string());
+    }
+
+    let b: BTreeMap<String, Entry> = b.into_iter().collect();
+    let a: BTreeMap<String, Entry> = a.into_iter().collect();
+
+    for (path, x) in &a {
+        let y = match b.get(path) {
+            Some(y) => y,
+            None => {
+                ret.push(format!("{} only in the first", path));
+                continue;
+            }
+        };
+        if x.kind != y.kind {
+            ret.push(format!(
+                "{} type: {} vs {}",
+                path, x.kind as char, y.kind as char
+            ));
+        }
+        if x.mode != y.mode {
+            ret.push(format!("{} mode: {:o} vs {:o}", path, x.mode, y.mode));
+        }
+        if (x.uid, x.gid) != (y.uid, y.gid) {
+            ret.push(format!(
+                "{} owner: {}:{} vs {}:{}",
+                path, x.uid, x.gid, y.uid, y.gid
+            ));
+        }
+        if x.mtime != y.mtime {
+            ret.push(format!("{} mtime: {} vs {}", path, x.mtime, y.mtime));
+        }
+        if x.link != y.link {
+            ret.push(format!("{} link: {:?} vs {:?}", path, x.link, y.link));
+        }
+        if x.content != y.content {
+            ret.push(format!(
+                "{} content: {}",
+                path,
+                first_difference(&x.content, &y.content)
+            ));
+        }
+    }
+    for path in b.keys().filter(|path| !a.contains_key(*path)) {
+        ret.push(format!("{} only in the second", path));
+    }
+
+    Ok(ret)
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> String {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(offset) => format!(
+            "first differs at byte {} (of {} vs {})",
+            offset,
+            a.len(),
+            b.len()
+        ),
+        None => format!("lengths differ: {} vs {}", a.len(), b.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deb::tests::TestPackage;
+
+    #[test]
+    fn compare_debs() {
+        let package = TestPackage::new("one");
+        let write = || package.deb("foo", "1.0~bionic", Some(1000));
+
+        let first = write();
+        assert!(super::compare_debs(&first, &write()).unwrap().is_empty());
+
+        package.set_script("two");
+        let differences = super::compare_debs(&first, &write()).unwrap();
+        assert!(
+            differences.iter().any(|d| d
+                .starts_with("data.tar.xz: usr/local/bin/foo content: first differs at byte 0")),
+            "{:?}",
+            differences
+        );
+        assert!(
+            differences
+                .iter()
+                .any(|d| d.starts_with("control.tar.xz: md5sums content")),
+            "{:?}",
+            differences
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use crate::graph;
+use crate::specs::Package;
+use crate::Release;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Waiting,
+    Running,
+    Cached,
+    Built,
+    Failed,
+    /// A local dependency failed, so this was never attempted.
+    Skipped,
+}
+
+impl State {
+    fn succeeded(self) -> bool {
+        State::Cached == self || State::Built == self
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            State::Waiting => "waiting",
+            State::Running => "running",
+            State::Cached => "cached",
+            State::Built => "ok",
+            State::Failed => "FAILED",
+            State::Skipped => "skipped",
+        }
+    }
+}
+
+/// A package index and a release index.
+pub type Job = (usize, usize);
+
+/// Which package/release pairs can be built, given which have finished.
+///
+/// A job is ready once every local package it depends on has succeeded for the same release.
+pub struct Schedule<'p> {
+    packages: &'p [Package],
+    releases: Vec<Release>,
+    deps: Vec<Vec<usize>>,
+    states: Vec<Vec<State>>,
+}
+
+impl<'p> Schedule<'p> {
+    pub fn new(packages: &'p [Package], releases: &[Release]) -> Schedule<'p> {
+        let deps = packages
+            .iter()
+            .map(|package| {
+                graph::local_deps(package, packages)
+                    .into_iter()
+                    .map(|dep| {
+                        packages
+                            .iter()
+                            .position(|p| p.name == dep)
+                            .expect("local deps are local")
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Schedule {
+            packages,
+            releases: releases.to_vec(),
+            deps,
+            states: vec![vec![State::Waiting; releases.len()]; packages.len()],
+        }
+    }
+
+    /// The next job which can start, marking it as running.
+    ///
+    /// Jobs whose dependencies have failed are marked as skipped along the way.
+    pub fn next(&mut self) -> Option<Job> {
+        // packages are in build order, so skips propagate in a single pass
+        for package in 0..self.packages.len() {
+            for release in 0..self.releases.len() {
+                if State::Waiting != self.states[package][release] {
+                    continue;
+                }
+
+                let deps: Vec<State> = self.deps[package]
+                    .iter()
+                    .map(|&dep| self.states[dep][release])
+                    .collect();
+
+                if deps
+                    .iter()
+                    .any(|&s| State::Failed == s || State::Skipped == s)
+                {
+                    self.states[package][release] = State::Skipped;
+                } else if deps.iter().all(|s| s.succeeded()) {
+                    self.states[package][release] = State::Running;
+                    return Some((package, release));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn finish(&mut self, (package, release): Job, state: State) {
+        assert_eq!(State::Running, self.states[package][release]);
+        self.states[package][release] = state;
+    }
+
+    pub fn package(&self, (package, _): Job) -> &'p Package {
+        &self.packages[package]
+    }
+
+    pub fn release(&self, (_, release): Job) -> Release {
+        self.releases[release]
+    }
+
+    /// The jobs for the local packages `job` depends on, for the same release.
+    pub fn deps(&self, (package, release): Job) -> impl Iterator<Item = Job> + '_ {
+        self.deps[package].iter().map(move |&dep| (dep, release))
+    }
+
+    pub fn failures(&self) -> usize {
+        self.states
+            .iter()
+            .flatten()
+            .filter(|&&s| State::Failed == s || State::Skipped == s)
+            .count()
+    }
+
+    /// A table of packages against releases.
+    pub fn summary(&self) -> String {
+        let width = self
+            .packages
+            .iter()
+            .map(|p| p.name.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut ret = format!("{:width$}", "", width = width);
+        for release in &self.releases {
+            ret.push_str(&format!(" {:>8}", release.codename()));
+        }
+        ret.push('\n');
+
+        for (package, states) in self.packages.iter().zip(&self.states) {
+            ret.push_str(&format!("{:width$}", package.name, width = width));
+            for state in states {
+                ret.push_str(&format!(" {:>8}", state.label()));
+            }
+            ret.push('\n');
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use super::State;
+    use crate::specs::Package;
+    use crate::Release;
+
+    #[test]
+    fn deps_and_skips() {
+        let mut app = Package::new("app", ".");
+        app.build_dep.push("lib".to_string());
+        let packages = vec![Package::new("lib", "."), app, Package::new("other", ".")];
+        let releases = [Release::UbuntuBionic, Release::UbuntuXenial];
+        let mut schedule = Schedule::new(&packages, &releases);
+
+        assert_eq!(Some((0, 0)), schedule.next());
+        assert_eq!(Some((0, 1)), schedule.next());
+        assert_eq!(Some((2, 0)), schedule.next());
+        assert_eq!(Some((2, 1)), schedule.next());
+        assert_eq!(None, schedule.next());
+
+        schedule.finish((0, 0), State::Built);
+        schedule.finish((0, 1), State::Failed);
+        assert_eq!(Some((1, 0)), schedule.next());
+        assert_eq!(None, schedule.next());
+
+        schedule.finish((1, 0), State::Cached);
+        schedule.finish((2, 0), State::Built);
+        schedule.finish((2, 1), State::Built);
+        assert_eq!(None, schedule.next());
+        assert_eq!(2, schedule.failures());
+
+        assert_eq!(
+            "        bionic   xenial\n\
+             lib         ok   FAILED\n\
+             app     cached  skipped\n\
+             other       ok       ok\n",
+            schedule.summary()
+        );
+    }
+}
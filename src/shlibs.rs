@@ -0,0 +1,305 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use goblin::elf::Elf;
+use log::warn;
+
+use crate::graph::mentioned;
+
+/// The `DT_NEEDED` entries of an ELF file, or `None` if it isn't ELF at all.
+pub fn needed(content: &[u8]) -> Result<Option<Vec<String>>, Error> {
+    if !content.starts_with(b"\x7fELF") {
+        return Ok(None);
+    }
+
+    let elf = Elf::parse(content)?;
+    Ok(Some(elf.libraries.iter().map(|s| s.to_string()).collect()))
+}
+
+/// Every library needed by the regular files amongst `files`, which are absolute inside `root`.
+pub fn needed_by(root: &Path, files: &[&str]) -> Result<BTreeSet<String>, Error> {
+    let mut ret = BTreeSet::new();
+    for file in files {
+        let host = root.join(&file[1..]);
+        if !fs::symlink_metadata(&host)?.file_type().is_file() {
+            continue;
+        }
+        let content = fs::read(&host)?;
+        if let Some(libraries) =
+            needed(&content).with_context(|| format_err!("parsing {:?} as ELF", file))?
+        {
+            ret.extend(libraries);
+        }
+    }
+    Ok(ret)
+}
+
+/// Split a soname into the name and version used by `shlibs` files,
+/// i.e. `libfoo.so.1` is `libfoo 1`, and `libfoo-1.2.so` is `libfoo 1.2`.
+pub fn split_soname(soname: &str) -> Option<(&str, &str)> {
+    if let Some(pos) = soname.find(".so.") {
+        return Some((&soname[..pos], &soname[pos + ".so.".len()..]));
+    }
+
+    let stem = soname.strip_suffix(".so")?;
+    let dash = stem.rfind('-')?;
+    let version = &stem[dash + 1..];
+    if version.starts_with(|c: char| c.is_ascii_digit()) {
+        Some((&stem[..dash], version))
+    } else {
+        None
+    }
+}
+
+/// Finds which package provides a library, inside a build root.
+#[derive(Clone, Debug, Default)]
+pub struct Resolver {
+    /// File name (e.g. `libusb-1.0.so.0`) to the installed package which contains it.
+    files: HashMap<String, String>,
+    /// (name, version) to the dependency from the package's `shlibs` file.
+    shlibs: HashMap<(String, String), String>,
+    /// Installed package to its version.
+    versions: HashMap<String, String>,
+    /// File name to the dependency on the locally built package which contains it.
+    local: HashMap<String, String>,
+}
+
+impl Resolver {
+    /// Read dpkg's database from inside `root`.
+    pub fn load<P: AsRef<Path>>(root: P) -> Result<Resolver, Error> {
+        let dpkg = root.as_ref().join("var/lib/dpkg");
+        let mut resolver = Resolver::default();
+
+        for entry in fs::read_dir(dpkg.join("info"))? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if let Some(package) = name.strip_suffix(".list") {
+                let package = strip_arch(package);
+                for line in fs::read_to_string(&path)?.lines() {
+                    if !line.contains(".so") || !line.contains("/lib") {
+                        continue;
+                    }
+                    if let Some(file) = line.rsplit('/').next() {
+                        resolver.files.insert(file.to_string(), package.to_string());
+                    }
+                }
+            } else if name.ends_with(".shlibs") {
+                resolver.load_shlibs(&fs::read_to_string(&path)?);
+            }
+        }
+
+        resolver.load_status(
+            &fs::read_to_string(dpkg.join("status"))
+                .with_context(|| format_err!("reading dpkg status"))?,
+        );
+
+        Ok(resolver)
+    }
+
+    /// Lines like `libusb-1.0 0 libusb-1.0-0 (>= 2:1.0.9)`, with an optional `type:` prefix.
+    fn load_shlibs(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                // only the default (non-udeb) type is interesting
+                [kind, ..] if kind.ends_with(':') => continue,
+                [name, version, dep @ ..] if !dep.is_empty() => {
+                    self.shlibs
+                        .insert((name.to_string(), version.to_string()), dep.join(" "));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn load_status(&mut self, text: &str) {
+        for stanza in text.split("\n\n") {
+            let field = |key: &str| {
+                stanza
+                    .lines()
+                    .find(|line| line.starts_with(key))
+                    .map(|line| line[key.len()..].trim())
+            };
+            if let (Some(package), Some(version)) = (field("Package:"), field("Version:")) {
+                self.versions
+                    .insert(package.to_string(), version.to_string());
+            }
+        }
+    }
+
+    /// Record that `file` will be provided by a package we're building.
+    pub fn add_local(&mut self, file: &str, dep: String) {
+        self.local.insert(file.to_string(), dep);
+    }
+
+    /// The dependency which will provide `soname`, preferring local packages, then `shlibs`.
+    pub fn depends(&self, soname: &str) -> Option<String> {
+        if let Some(local) = self.local.get(soname) {
+            return Some(local.to_string());
+        }
+
+        let package = self.files.get(soname)?;
+        if let Some((name, version)) = split_soname(soname) {
+            if let Some(dep) = self.shlibs.get(&(name.to_string(), version.to_string())) {
+                return Some(dep.to_string());
+            }
+        }
+
+        // without `shlibs`, anything from the same upstream release should do
+        Some(match self.versions.get(package) {
+            Some(version) => format!("{} (>= {})", package, upstream_version(version)),
+            None => package.to_string(),
+        })
+    }
+}
+
+/// A Debian version without its revision, i.e. `2:1.0.21-2ubuntu1` is `2:1.0.21`.
+fn upstream_version(version: &str) -> &str {
+    match version.rfind('-') {
+        Some(dash) => &version[..dash],
+        None => version,
+    }
+}
+
+fn strip_arch(package: &str) -> &str {
+    package.split(':').next().expect("split is never empty")
+}
+
+/// The manual dependencies, plus any automatic ones for packages they don't already mention.
+pub fn merge<I: IntoIterator<Item = String>>(manual: &[String], automatic: I) -> Vec<String> {
+    let mut ret = manual.to_vec();
+    for dep in automatic {
+        let already = ret
+            .iter()
+            .flat_map(|d| mentioned(d))
+            .any(|name| mentioned(&dep).any(|other| other == name));
+        if !already {
+            ret.push(dep);
+        }
+    }
+    ret
+}
+
+/// Warn about, and skip, libraries which nothing seems to provide.
+pub fn resolve_all<'s, I: IntoIterator<Item = &'s String>>(
+    resolver: &Resolver,
+    sonames: I,
+) -> Vec<String> {
+    let mut ret = Vec::new();
+    for soname in sonames {
+        match resolver.depends(soname) {
+            Some(dep) => {
+                if !ret.contains(&dep) {
+                    ret.push(dep);
+                }
+            }
+            None => warn!("no package provides {:?}", soname),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn split_soname() {
+        assert_eq!(
+            Some(("libusb-1.0", "0")),
+            super::split_soname("libusb-1.0.so.0")
+        );
+        assert_eq!(
+            Some(("libglib", "2.0")),
+            super::split_soname("libglib-2.0.so")
+        );
+        assert_eq!(None, super::split_soname("libfoo.so"));
+    }
+
+    #[test]
+    fn upstream_version() {
+        assert_eq!("2.27", super::upstream_version("2.27-3ubuntu1"));
+        assert_eq!("2:1.0.21", super::upstream_version("2:1.0.21-2"));
+        assert_eq!("1.2-rc1", super::upstream_version("1.2-rc1-1"));
+        assert_eq!("1.0", super::upstream_version("1.0"));
+    }
+
+    #[test]
+    fn needed() {
+        assert_eq!(None, super::needed(b"#!/bin/sh\n").unwrap());
+        let us = fs::read(std::env::current_exe().unwrap()).unwrap();
+        assert!(super::needed(&us).unwrap().is_some());
+    }
+
+    #[test]
+    fn resolve() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let info = dir.path().join("var/lib/dpkg/info");
+        fs::create_dir_all(&info).unwrap();
+        fs::write(
+            info.join("libusb-1.0-0:amd64.list"),
+            "/.\n/lib/x86_64-linux-gnu/libusb-1.0.so.0\n/usr/share/doc/libusb-1.0-0\n",
+        )
+        .unwrap();
+        fs::write(
+            info.join("libusb-1.0-0:amd64.shlibs"),
+            "libusb-1.0 0 libusb-1.0-0 (>= 2:1.0.9)\nudeb: libusb-1.0 0 libusb-1.0-0-udeb\n",
+        )
+        .unwrap();
+        fs::write(
+            info.join("libc6:amd64.list"),
+            "/lib/x86_64-linux-gnu/libc.so.6\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("var/lib/dpkg/status"),
+            "Package: libc6\nStatus: install ok installed\nVersion: 2.27-3ubuntu1\n\n\
+             Package: libusb-1.0-0\nVersion: 2:1.0.21-2\n",
+        )
+        .unwrap();
+
+        let mut resolver = super::Resolver::load(dir.path()).unwrap();
+        resolver.add_local("libfoo.so.1", "libfoo1 (= 1.0~bionic)".to_string());
+
+        let needed: Vec<String> = vec![
+            "libc.so.6",
+            "libusb-1.0.so.0",
+            "libfoo.so.1",
+            "libnope.so.3",
+        ]
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+        let automatic = super::resolve_all(&resolver, &needed);
+        assert_eq!(
+            vec![
+                "libc6 (>= 2.27)",
+                "libusb-1.0-0 (>= 2:1.0.9)",
+                "libfoo1 (= 1.0~bionic)",
+            ],
+            automatic
+        );
+
+        assert_eq!(
+            vec![
+                "libc6 (>= 2.28)",
+                "libusb-1.0-0 (>= 2:1.0.9)",
+                "libfoo1 (= 1.0~bionic)"
+            ],
+            super::merge(&["libc6 (>= 2.28)".to_string()], automatic)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use log::debug;
+
+use crate::sources::sha256_file;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, fifos and sockets, which we never expect to package.
+    Other,
+}
+
+/// Everything about a path which could end up in a package.
+///
+/// Owners are as seen from the host, i.e. not translated through the sandbox's id map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: Type,
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Zero for anything but regular files.
+    pub size: u64,
+    /// Only for regular files, and only those we could read.
+    pub sha256: Option<String>,
+    pub target: Option<PathBuf>,
+}
+
+/// Every path in a root, as absolute paths inside that root.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub entries: BTreeMap<String, Entry>,
+    /// For files, what was on disk when they were hashed.
+    stamps: BTreeMap<String, Stamp>,
+}
+
+/// Enough to tell that a file hasn't changed, without reading it again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Stamp {
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Changeset {
+    pub added: Vec<String>,
+    /// Paths whose content, type, or (for non-directories) metadata changed.
+    pub modified: Vec<String>,
+    /// Directories which are still directories, but have a different mode or owner.
+    pub dir_metadata: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+/// Trees which are never packaged, and which the sandbox itself writes to during a build:
+/// the sources, scratch space, and apt's state, which belongs to apt's own user.
+const SKIPPED: &[&str] = &["/build", "/tmp", "/var/cache/apt", "/var/lib/apt"];
+
+pub fn take<P: AsRef<Path>>(root: P) -> Result<Snapshot, Error> {
+    retake(root, &Snapshot::default())
+}
+
+/// Snapshot `root` again, only hashing files which have changed since `before`.
+///
+/// Directories we can't read, e.g. ones owned by another of the sandbox's users, are
+/// recorded, but not their contents; nor are the hashes of such files.
+pub fn retake<P: AsRef<Path>>(root: P, before: &Snapshot) -> Result<Snapshot, Error> {
+    let root = root.as_ref();
+    let mut snapshot = Snapshot::default();
+
+    let walk = walkdir::WalkDir::new(root)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|item| match inside(root, item.path()) {
+            Ok(inside) => !SKIPPED.contains(&inside.as_str()),
+            Err(_) => true,
+        });
+
+    for item in walk {
+        let item = match item {
+            Ok(item) => item,
+            Err(ref e) if Some(libc::EACCES) == e.io_error().and_then(|e| e.raw_os_error()) => {
+                debug!("not snapshotting unreadable {:?}", e.path());
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let path = item.path();
+        let inside = inside(root, path)?;
+
+        let meta =
+            fs::symlink_metadata(path).with_context(|| format_err!("snapshotting {:?}", path))?;
+        let stamp = Stamp {
+            inode: meta.ino(),
+            size: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        };
+        let unchanged = match before.stamps.get(&inside) {
+            Some(old) if *old == stamp => before.entries.get(&inside),
+            _ => None,
+        };
+
+        let entry = read_entry(path, &meta, unchanged)
+            .with_context(|| format_err!("snapshotting {:?}", path))?;
+        if Type::File == entry.kind {
+            snapshot.stamps.insert(inside.to_string(), stamp);
+        }
+        snapshot.entries.insert(inside, entry);
+    }
+
+    Ok(snapshot)
+}
+
+/// `path`, as an absolute path inside `root`.
+fn inside(root: &Path, path: &Path) -> Result<String, Error> {
+    let inside = path
+        .strip_prefix(root)
+        .expect("walkdir only returns children")
+        .to_str()
+        .ok_or_else(|| format_err!("non-utf-8 path: {:?}", path))?;
+    Ok(format!("/{}", inside))
+}
+
+/// The `Entry` for `path`, taking the hash from `unchanged`, if it's given.
+fn read_entry(path: &Path, meta: &fs::Metadata, unchanged: Option<&Entry>) -> Result<Entry, Error> {
+    let file_type = meta.file_type();
+
+    let kind = if file_type.is_file() {
+        Type::File
+    } else if file_type.is_dir() {
+        Type::Dir
+    } else if file_type.is_symlink() {
+        Type::Symlink
+    } else {
+        Type::Other
+    };
+
+    Ok(Entry {
+        kind,
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        size: match kind {
+            Type::File => meta.len(),
+            _ => 0,
+        },
+        sha256: match (kind, unchanged) {
+            (Type::File, Some(old)) => old.sha256.clone(),
+            (Type::File, None) => match sha256_file(path) {
+                Ok(sha256) => Some(sha256),
+                Err(ref e) if Some(libc::EACCES) == io_error(e) => {
+                    debug!("not hashing unreadable {:?}", path);
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+            _ => None,
+        },
+        target: match kind {
+            Type::Symlink => Some(fs::read_link(path)?),
+            _ => None,
+        },
+    })
+}
+
+fn io_error(e: &Error) -> Option<i32> {
+    e.downcast_ref::<io::Error>().and_then(|e| e.raw_os_error())
+}
+
+pub fn diff(before: &Snapshot, after: &Snapshot) -> Changeset {
+    let mut changes = Changeset::default();
+
+    for (path, old) in &before.entries {
+        match after.entries.get(path) {
+            None => changes.deleted.push(path.to_string()),
+            Some(new) if new == old => (),
+            Some(new) if Type::Dir == old.kind && Type::Dir == new.kind => {
+                changes.dir_metadata.push(path.to_string())
+            }
+            Some(_) => changes.modified.push(path.to_string()),
+        }
+    }
+
+    for path in after.entries.keys() {
+        if !before.entries.contains_key(path) {
+            changes.added.push(path.to_string());
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::Type;
+
+    #[test]
+    fn diff() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("usr/local/lib")).unwrap();
+        fs::write(root.join("usr/local/lib/keep"), "same").unwrap();
+        fs::write(root.join("usr/local/lib/change"), "before").unwrap();
+        fs::write(root.join("usr/local/lib/gone"), "").unwrap();
+
+        let before = super::take(root).unwrap();
+        assert_eq!(Type::Dir, before.entries["/"].kind);
+        assert_eq!(
+            Some("0967115f2813a3541eaef77de9d9d5773f1c0c04314b0bbfe4ff3b3b1c55b5d5".to_string()),
+            before.entries["/usr/local/lib/keep"].sha256
+        );
+
+        fs::write(root.join("usr/local/lib/change"), "after!").unwrap();
+        fs::remove_file(root.join("usr/local/lib/gone")).unwrap();
+        symlink("keep", root.join("usr/local/lib/link")).unwrap();
+        fs::set_permissions(root.join("usr/local"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let after = super::take(root).unwrap();
+        assert_eq!(
+            Some("keep".into()),
+            after.entries["/usr/local/lib/link"].target
+        );
+
+        let changes = super::diff(&before, &after);
+        assert_eq!(vec!["/usr/local/lib/link"], changes.added);
+        assert_eq!(vec!["/usr/local/lib/change"], changes.modified);
+        assert_eq!(vec!["/usr/local"], changes.dir_metadata);
+        assert_eq!(vec!["/usr/local/lib/gone"], changes.deleted);
+    }
+
+    #[test]
+    fn unreadable() {
+        // root can read anything, so read as someone else; otherwise, revoke our own access
+        let is_root = nix::unistd::geteuid().is_root();
+        let (dir_mode, file_mode) = if is_root { (0o700, 0o600) } else { (0, 0) };
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+        fs::set_permissions(root, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(root.join("usr/local/partial")).unwrap();
+        fs::write(root.join("usr/local/partial/foo.deb"), "").unwrap();
+        fs::write(root.join("usr/local/private"), "").unwrap();
+        fs::create_dir_all(root.join("var/lib/apt/lists")).unwrap();
+        fs::create_dir_all(root.join("build/src")).unwrap();
+        let partial = root.join("usr/local/partial");
+        fs::set_permissions(&partial, fs::Permissions::from_mode(dir_mode)).unwrap();
+        let private = root.join("usr/local/private");
+        fs::set_permissions(&private, fs::Permissions::from_mode(file_mode)).unwrap();
+
+        let snapshot = if is_root {
+            as_nobody(|| super::take(root))
+        } else {
+            super::take(root)
+        };
+        fs::set_permissions(&partial, fs::Permissions::from_mode(0o700)).unwrap();
+        let snapshot = snapshot.unwrap();
+
+        assert_eq!(Type::Dir, snapshot.entries["/usr/local/partial"].kind);
+        assert!(!snapshot.entries.contains_key("/usr/local/partial/foo.deb"));
+        assert_eq!(Type::File, snapshot.entries["/usr/local/private"].kind);
+        assert_eq!(None, snapshot.entries["/usr/local/private"].sha256);
+        assert!(snapshot.entries.contains_key("/var/lib"));
+        assert!(!snapshot.entries.contains_key("/var/lib/apt"));
+        assert!(!snapshot.entries.contains_key("/build"));
+    }
+
+    /// Run `f` as `nobody`, on a thread of its own: unlike `setuid(3)`, the raw syscall
+    /// only changes the calling thread.
+    fn as_nobody<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let nobody = 65534;
+                    let ret = unsafe { libc::syscall(libc::SYS_setresuid, nobody, nobody, nobody) };
+                    assert_eq!(0, ret);
+                    f()
+                })
+                .join()
+                .unwrap()
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::format_err;
+use anyhow::Context;
+use anyhow::Error;
+use log::info;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Ensure the tarball is in the cache, and has the expected contents.
+///
+/// The cache is keyed on the checksum, so a changed upstream is never silently used.
+pub fn fetch<P: AsRef<Path>>(cache: P, url: &str, sha256: &str) -> Result<PathBuf, Error> {
+    let mut path = cache.as_ref().to_path_buf();
+    path.push("sources");
+    path.push(sha256);
+    fs::create_dir_all(&path)?;
+
+    let name = url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| format_err!("url has no file name: {:?}", url))?;
+    path.push(name);
+
+    if path.is_file() && sha256 == sha256_file(&path)? {
+        return Ok(path);
+    }
+
+    info!("downloading {} to {:?}", url, path);
+
+    // only checked downloads ever appear at `path`, so a bad one is retried next time
+    let partial = path.with_file_name(format!("{}.partial", name));
+    match fs::remove_file(&partial) {
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => (),
+        other => other?,
+    }
+
+    // the checksum is fixed, so there's never any need to check for a newer version
+    download_if_newer::ensure_downloaded_slop(
+        url,
+        &partial,
+        Duration::from_secs(u64::from(u32::MAX)),
+    )
+    .with_context(|| format_err!("downloading {:?} to {:?}", url, partial))?;
+
+    let actual = sha256_file(&partial)?;
+    if sha256 != actual {
+        fs::remove_file(&partial)?;
+        bail!(
+            "checksum mismatch for {}: expected sha256={}, but it's sha256={}",
+            url,
+            sha256,
+            actual
+        );
+    }
+
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Unpack a `.tar`, `.tar.gz`, `.tar.xz` or `.tar.zst` into `dest`, choosing by file name.
+pub fn unpack_archive<S: AsRef<Path>, D: AsRef<Path>>(src: S, dest: D) -> Result<(), Error> {
+    let src = src.as_ref();
+    let reader = open_archive(src)?;
+
+    fs::create_dir_all(&dest)?;
+    tar::Archive::new(reader)
+        .unpack(&dest)
+        .with_context(|| format_err!("unpacking {:?} to {:?}", src, dest.as_ref()))?;
+    Ok(())
+}
+
+/// The newest modification time of the files in an archive, in seconds, as recorded
+/// inside it, so it's the same however, and whenever, it's unpacked.
+pub fn archive_mtime<S: AsRef<Path>>(src: S) -> Result<Option<i64>, Error> {
+    let src = src.as_ref();
+    let mut newest = None;
+    for entry in tar::Archive::new(open_archive(src)?).entries()? {
+        let entry = entry.with_context(|| format_err!("reading {:?}", src))?;
+        if tar::EntryType::Regular == entry.header().entry_type() {
+            newest = newest.max(Some(entry.header().mtime()? as i64));
+        }
+    }
+    Ok(newest)
+}
+
+fn open_archive(src: &Path) -> Result<Box<dyn io::Read>, Error> {
+    let name = src
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format_err!("invalid archive name: {:?}", src))?;
+    let file = fs::File::open(src)?;
+
+    Ok(if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if name.ends_with(".tar.xz") {
+        Box::new(xz2::read::XzDecoder::new(file))
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tar.zstd") {
+        Box::new(zstd::Decoder::new(file)?)
+    } else if name.ends_with(".tar") {
+        Box::new(file)
+    } else {
+        bail!("unsupported archive type: {:?}", name);
+    })
+}
+
+/// Where a `LOCAL` source lives, checking it's there.
+pub fn local<P: AsRef<Path>>(spec_dir: P, path: &str) -> Result<PathBuf, Error> {
+    let full = spec_dir.as_ref().join(path);
+    ensure!(
+        full.is_dir(),
+        "local source {:?} is not a directory (at {:?})",
+        path,
+        full
+    );
+    Ok(full)
+}
+
+/// Copy a `LOCAL` source's contents into `dest`.
+pub fn copy_local<S: AsRef<Path>, D: AsRef<Path>>(src: S, dest: D) -> Result<(), Error> {
+    fs::create_dir_all(&dest)?;
+    let mut options = fs_extra::dir::CopyOptions::new();
+    options.content_only = true;
+    options.overwrite = true;
+    fs_extra::dir::copy(&src, &dest, &options)
+        .map_err(|e| format_err!("copying {:?} to {:?}: {}", src.as_ref(), dest.as_ref(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn unpack_gz() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let archive = dir.path().join("foo-1.0.tar.gz");
+        {
+            let gz = flate2::write::GzEncoder::new(
+                fs::File::create(&archive).unwrap(),
+                flate2::Compression::fast(),
+            );
+            let mut tar = tar::Builder::new(gz);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_mtime(1_500_000_000);
+            header.set_cksum();
+            tar.append_data(&mut header, "foo-1.0/README", &b"hello"[..])
+                .unwrap();
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+
+        let dest = dir.path().join("src");
+        super::unpack_archive(&archive, &dest).unwrap();
+        assert_eq!(
+            "hello",
+            fs::read_to_string(dest.join("foo-1.0/README")).unwrap()
+        );
+        assert_eq!(Some(1_500_000_000), super::archive_mtime(&archive).unwrap());
+
+        fs::write(dir.path().join("test"), b"test").unwrap();
+        assert_eq!(
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+            super::sha256_file(dir.path().join("test")).unwrap()
+        );
+    }
+}
@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+
+use conch_parser::ast;
+use conch_parser::ast::DefaultSimpleWord;
 use conch_parser::ast::DefaultWord;
 use conch_parser::ast::SimpleWord;
 use anyhow::bail;
@@ -7,7 +11,13 @@ use anyhow::format_err;
 use anyhow::Error;
 use anyhow::Context;
 
-pub fn load(from: &str) -> Result<Vec<Vec<String>>, Error> {
+use crate::patterns::Patterns;
+use crate::specs::check_version;
+use crate::specs::Command;
+use crate::specs::Output;
+use crate::specs::Package;
+
+pub fn load(from: &str) -> Result<Vec<List>, Error> {
     split(from)
         .into_iter()
         .map(
@@ -31,14 +41,209 @@ fn strip_comments(from: &str) -> String {
         .join("\n")
 }
 
-fn tokens(from: &str) -> Result<Vec<String>, Error> {
+/// A block of shell: pipelines joined by `&&` or `||`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct List {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pipeline {
+    pub negated: bool,
+    pub commands: Vec<Simple>,
+}
+
+/// `FOO=bar make -j 2 install 2>errors`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Simple {
+    pub env: Vec<(String, Word)>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redirect {
+    pub fd: Option<u16>,
+    pub op: &'static str,
+    pub target: Word,
+}
+
+/// A word, as it was split by the shell; the parts are concatenated.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Word(pub Vec<Part>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Part {
+    Literal(String),
+    /// `$FOO`, `${FOO}` or `"$FOO"`: expanded without field splitting
+    Var(String),
+    /// An unquoted `*`, `?`, `[`, `]` or `~`, left for the shell in the container.
+    Glob(char),
+}
+
+type Vars = HashMap<String, String>;
+
+impl List {
+    pub fn expand(&self, vars: &Vars) -> Result<List, Error> {
+        Ok(List {
+            first: self.first.expand(vars)?,
+            rest: self
+                .rest
+                .iter()
+                .map(|(conn, p)| Ok((*conn, p.expand(vars)?)))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let mut ret = self.first.render();
+        for (conn, pipeline) in &self.rest {
+            ret.push_str(match conn {
+                Connector::And => " && ",
+                Connector::Or => " || ",
+            });
+            ret.push_str(&pipeline.render());
+        }
+        ret
+    }
+
+    /// The command, if this is a lone command, with no pipes or lists.
+    fn single(&self) -> Option<&Simple> {
+        match self.first.commands.as_slice() {
+            [simple] if self.rest.is_empty() && !self.first.negated => Some(simple),
+            _ => None,
+        }
+    }
+}
+
+impl Pipeline {
+    fn expand(&self, vars: &Vars) -> Result<Pipeline, Error> {
+        Ok(Pipeline {
+            negated: self.negated,
+            commands: self
+                .commands
+                .iter()
+                .map(|c| c.expand(vars))
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    fn render(&self) -> String {
+        let commands = self
+            .commands
+            .iter()
+            .map(|c| c.render())
+            .collect::<Vec<_>>()
+            .join(" | ");
+        match self.negated {
+            true => format!("! {}", commands),
+            false => commands,
+        }
+    }
+}
+
+impl Simple {
+    fn expand(&self, vars: &Vars) -> Result<Simple, Error> {
+        Ok(Simple {
+            env: self
+                .env
+                .iter()
+                .map(|(k, v)| Ok((k.to_string(), v.expand(vars)?)))
+                .collect::<Result<_, Error>>()?,
+            words: self
+                .words
+                .iter()
+                .map(|w| w.expand(vars))
+                .collect::<Result<_, Error>>()?,
+            redirects: self
+                .redirects
+                .iter()
+                .map(|r| {
+                    Ok(Redirect {
+                        fd: r.fd,
+                        op: r.op,
+                        target: r.target.expand(vars)?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    fn render(&self) -> String {
+        let env = self
+            .env
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v.render()));
+        let words = self.words.iter().map(|w| w.render());
+        let redirects = self.redirects.iter().map(|r| {
+            let fd = r.fd.map(|fd| fd.to_string()).unwrap_or_default();
+            format!("{}{}{}", fd, r.op, r.target.render())
+        });
+        env.chain(words)
+            .chain(redirects)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Word {
+    fn expand(&self, vars: &Vars) -> Result<Word, Error> {
+        Ok(Word(
+            self.0
+                .iter()
+                .map(|part| {
+                    Ok(match part {
+                        Part::Var(name) => Part::Literal(
+                            vars.get(name)
+                                .ok_or_else(|| anyhow!("undefined variable: {:?}", name))?
+                                .to_string(),
+                        ),
+                        other => other.clone(),
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+        ))
+    }
+
+    fn render(&self) -> String {
+        if self.0.is_empty() {
+            return quote("");
+        }
+
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Literal(s) => quote(s),
+                Part::Var(name) => format!("\"${{{}}}\"", name),
+                Part::Glob(c) => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// The word as the shell would see it, ignoring any globbing.
+    pub fn text(&self) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Literal(s) => s.to_string(),
+                Part::Var(name) => format!("${{{}}}", name),
+                Part::Glob(c) => c.to_string(),
+            })
+            .collect()
+    }
+}
+
+fn tokens(from: &str) -> Result<List, Error> {
+    use conch_parser::ast::AndOr;
     use conch_parser::ast::Command;
-    use conch_parser::ast::ComplexWord;
-    use conch_parser::ast::ListableCommand;
-    use conch_parser::ast::PipeableCommand;
-    use conch_parser::ast::RedirectOrCmdWord;
     use conch_parser::ast::TopLevelCommand;
-    use conch_parser::ast::TopLevelWord;
     use conch_parser::lexer::Lexer;
     use conch_parser::parse::DefaultParser;
 
@@ -54,61 +259,434 @@ fn tokens(from: &str) -> Result<Vec<String>, Error> {
         Command::Job(_) => bail!("jobs not supported"),
     };
 
-    ensure!(cmd.rest.is_empty(), "rest not supported");
+    Ok(List {
+        first: pipeline(cmd.first)?,
+        rest: cmd
+            .rest
+            .into_iter()
+            .map(|and_or| {
+                Ok(match and_or {
+                    AndOr::And(p) => (Connector::And, pipeline(p)?),
+                    AndOr::Or(p) => (Connector::Or, pipeline(p)?),
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+    })
+}
 
-    let cmd = cmd.first;
+type ShellSimple =
+    ast::SimpleCommand<String, ast::TopLevelWord<String>, ast::Redirect<ast::TopLevelWord<String>>>;
 
-    let cmd = match cmd {
-        ListableCommand::Single(s) => s,
-        ListableCommand::Pipe(_, _) => bail!("pipes not supported"),
+fn pipeline<N, C, F>(
+    cmd: ast::ListableCommand<ast::PipeableCommand<N, Box<ShellSimple>, C, F>>,
+) -> Result<Pipeline, Error> {
+    use conch_parser::ast::ListableCommand;
+
+    let (negated, cmds) = match cmd {
+        ListableCommand::Single(s) => (false, vec![s]),
+        ListableCommand::Pipe(negated, cmds) => (negated, cmds),
     };
 
+    Ok(Pipeline {
+        negated,
+        commands: cmds.into_iter().map(simple).collect::<Result<_, Error>>()?,
+    })
+}
+
+fn simple<N, C, F>(cmd: ast::PipeableCommand<N, Box<ShellSimple>, C, F>) -> Result<Simple, Error> {
+    use conch_parser::ast::PipeableCommand;
+    use conch_parser::ast::RedirectOrCmdWord;
+    use conch_parser::ast::RedirectOrEnvVar;
+
     let cmd = match cmd {
         PipeableCommand::Simple(s) => s,
-        other => bail!("command types not supported: {:?}", other),
+        PipeableCommand::Compound(_) => bail!("compound commands not supported"),
+        PipeableCommand::FunctionDef(..) => bail!("function definitions not supported"),
     };
 
-    ensure!(
-        cmd.redirects_or_env_vars.is_empty(),
-        "no redirects or env vars"
-    );
+    let mut ret = Simple {
+        env: Vec::new(),
+        words: Vec::new(),
+        redirects: Vec::new(),
+    };
+
+    for item in cmd.redirects_or_env_vars {
+        match item {
+            RedirectOrEnvVar::Redirect(r) => ret.redirects.push(redirect(&r)?),
+            RedirectOrEnvVar::EnvVar(name, value) => ret.env.push((
+                name,
+                match value {
+                    Some(value) => word(&value)?,
+                    None => Word::default(),
+                },
+            )),
+        }
+    }
+
+    for item in cmd.redirects_or_cmd_words {
+        match item {
+            RedirectOrCmdWord::Redirect(r) => ret.redirects.push(redirect(&r)?),
+            RedirectOrCmdWord::CmdWord(w) => ret.words.push(word(&w)?),
+        }
+    }
+
+    Ok(ret)
+}
 
-    let cmd = cmd.redirects_or_cmd_words;
+fn redirect(r: &ast::Redirect<ast::TopLevelWord<String>>) -> Result<Redirect, Error> {
+    use conch_parser::ast::Redirect::*;
+    let (fd, op, target) = match r {
+        Read(fd, w) => (fd, "<", w),
+        Write(fd, w) => (fd, ">", w),
+        ReadWrite(fd, w) => (fd, "<>", w),
+        Append(fd, w) => (fd, ">>", w),
+        Clobber(fd, w) => (fd, ">|", w),
+        DupRead(fd, w) => (fd, "<&", w),
+        DupWrite(fd, w) => (fd, ">&", w),
+        Heredoc(..) => bail!("heredocs not supported"),
+    };
+
+    Ok(Redirect {
+        fd: *fd,
+        op,
+        target: word(target)?,
+    })
+}
 
-    cmd.into_iter()
-        .map(|c| {
-            match c {
-                RedirectOrCmdWord::Redirect(_) => bail!("redirect not supported"),
-                RedirectOrCmdWord::CmdWord(w) => Ok(w),
+fn word(w: &ast::TopLevelWord<String>) -> Result<Word, Error> {
+    use conch_parser::ast::ComplexWord;
+    let mut parts = Vec::new();
+    match &**w {
+        ComplexWord::Single(w) => word_parts(w, &mut parts)?,
+        ComplexWord::Concat(ws) => {
+            for w in ws {
+                word_parts(w, &mut parts)?;
             }
-            .and_then(|w| match &*w {
-                ComplexWord::Single(w) => literal_word(w),
-                other => bail!("complex word {:?}", other),
-            })
-        })
-        .collect()
+        }
+    }
+    Ok(Word(parts))
 }
 
-fn literal_word(word: &DefaultWord) -> Result<String, Error> {
+fn word_parts(word: &DefaultWord, parts: &mut Vec<Part>) -> Result<(), Error> {
     use conch_parser::ast::Word;
+    match word {
+        Word::SingleQuoted(w) => parts.push(Part::Literal(w.to_string())),
+        Word::DoubleQuoted(ws) => {
+            for w in ws {
+                parts.push(simple_part(w, true)?);
+            }
+        }
+        Word::Simple(w) => parts.push(simple_part(w, false)?),
+    }
+    Ok(())
+}
+
+fn simple_part(word: &DefaultSimpleWord, quoted: bool) -> Result<Part, Error> {
+    use conch_parser::ast::Parameter;
+
+    let special = |c: char| match quoted {
+        true => Part::Literal(c.to_string()),
+        false => Part::Glob(c),
+    };
+
     Ok(match word {
-        Word::SingleQuoted(w) => w.to_string(),
-        Word::DoubleQuoted(w) => bail!("unsupported double quotes"),
-        Word::Simple(w) => match w {
-            SimpleWord::Literal(s) => s.to_string(),
-            other => bail!("unsupported simple word {:?}", other),
-        },
+        SimpleWord::Literal(s) | SimpleWord::Escaped(s) => Part::Literal(s.to_string()),
+        SimpleWord::Param(Parameter::Var(name)) => Part::Var(name.to_string()),
+        SimpleWord::Star => special('*'),
+        SimpleWord::Question => special('?'),
+        SimpleWord::SquareOpen => special('['),
+        SimpleWord::SquareClose => special(']'),
+        SimpleWord::Tilde => special('~'),
+        SimpleWord::Colon => Part::Literal(":".to_string()),
+        other => bail!("unsupported simple word {:?}", other),
     })
 }
 
+/// Map the recognised verbs in a loaded script onto a `Package`.
+///
+/// Steps we don't recognise are kept as `Command::Run`: in the `build` phase until a
+/// build-system step (e.g. `fappa-cmake`) is seen, and in `install` after it.
+///
+/// Variables are expanded as the script is read: `FOO=bar` defines one for the rest of
+/// the spec, and `export FOO=bar` additionally sets it in the build environment.
+pub fn interpret(name: &str, blocks: Vec<List>) -> Result<Package, Error> {
+    let mut blocks = blocks.into_iter();
+
+    match blocks.next().as_ref().and_then(|b| b.single()) {
+        Some(simple) if is_version(simple) => (),
+        _ => bail!("spec must start with `source fappa-v1.bash`"),
+    }
+
+    let mut state = Interpreter {
+        package: Package::new(name, "."),
+        vars: Vars::new(),
+        dep: Vec::new(),
+        include_files: Vec::new(),
+        exclude_files: Vec::new(),
+        built: false,
+        packaged: false,
+    };
+
+    for block in blocks {
+        ensure!(
+            !state.packaged,
+            "nothing may follow fappa-package: {}",
+            block.render()
+        );
+        state
+            .block(&block)
+            .with_context(|| format_err!("interpreting {}", block.render()))?;
+    }
+
+    ensure!(state.packaged, "spec must end with `fappa-package`");
+
+    let mut package = state.package;
+    package.exclude_files =
+        Patterns::new(state.exclude_files).with_context(|| anyhow!("in EXCLUDE"))?;
+    package.outputs = vec![Output {
+        name: package.name.to_string(),
+        dep: state.dep,
+        include_files: Patterns::new(state.include_files).with_context(|| anyhow!("in INCLUDE"))?,
+    }];
+    Ok(package)
+}
+
+fn is_version(simple: &Simple) -> bool {
+    let words: Vec<String> = simple.words.iter().map(|w| w.text()).collect();
+    simple.env.is_empty()
+        && simple.redirects.is_empty()
+        && 2 == words.len()
+        && ("source" == words[0] || "." == words[0])
+        && "fappa-v1.bash" == words[1]
+}
+
+struct Interpreter {
+    package: Package,
+    vars: Vars,
+    dep: Vec<String>,
+    include_files: Vec<String>,
+    exclude_files: Vec<String>,
+    built: bool,
+    packaged: bool,
+}
+
+impl Interpreter {
+    fn block(&mut self, block: &List) -> Result<(), Error> {
+        let simple = match block.single() {
+            Some(simple) => simple,
+            None => return self.run(block.expand(&self.vars)?.render()),
+        };
+
+        if simple.words.is_empty() && simple.redirects.is_empty() {
+            for (key, value) in &simple.env {
+                let value = value.expand(&self.vars)?.text();
+                self.vars.insert(key.to_string(), value);
+            }
+            return Ok(());
+        }
+
+        let simple = simple.expand(&self.vars)?;
+
+        if !simple.env.is_empty() || !simple.redirects.is_empty() {
+            return self.run(simple.render());
+        }
+
+        self.step(simple)
+    }
+
+    fn step(&mut self, mut simple: Simple) -> Result<(), Error> {
+        let package = &mut self.package;
+        let words: Vec<String> = simple.words.iter().map(|w| w.text()).collect();
+        let words: Vec<&str> = words.iter().map(|s| s.as_str()).collect();
+        match words.as_slice() {
+            ["sudo", "apt", "install", args @ ..] | ["sudo", "apt-get", "install", args @ ..] => {
+                package.build_dep.extend(
+                    args.iter()
+                        .filter(|arg| !arg.starts_with('-'))
+                        .map(|arg| arg.to_string()),
+                )
+            }
+            ["git-export", repo, branch, sha, dest] => package.source.push(Command::Clone {
+                repo: repo.to_string(),
+                branch: Some(branch.to_string()),
+                sha: sha.to_string(),
+                dest: dest.to_string(),
+            }),
+            ["git-export", ..] => bail!("usage: git-export url branch sha dest"),
+            ["fappa-fetch", url, checksum, dest] => package.source.push(
+                crate::specs::parse_command(format!("FETCH {} {} {}", url, checksum, dest))?,
+            ),
+            ["fappa-fetch", ..] => bail!("usage: fappa-fetch url sha256=... dest"),
+            ["fappa-local", path, dest] => package.source.push(Command::Local {
+                path: path.to_string(),
+                dest: dest.to_string(),
+            }),
+            ["fappa-local", ..] => bail!("usage: fappa-local path dest"),
+            ["fappa-patches", dir, dest] => package.source.push(Command::Patches {
+                dir: dir.to_string(),
+                dest: dest.to_string(),
+            }),
+            ["fappa-patches", ..] => bail!("usage: fappa-patches dir dest"),
+            ["fappa-cmake"] => self.build_system(Command::CMake),
+            ["fappa-autoreconf"] => self.build_system(Command::Autoreconf),
+            ["fappa-meson", args @ ..] => self.build_system(Command::Meson { args: owned(args) }),
+            ["fappa-make", args @ ..] => self.build_system(Command::Make { args: owned(args) }),
+            ["fappa-cargo", args @ ..] => self.build_system(Command::Cargo { args: owned(args) }),
+            ["fappa-setup-py", args @ ..] => {
+                self.build_system(Command::SetupPy { args: owned(args) })
+            }
+            ["fappa-package"] => self.packaged = true,
+            ["cd", dir] => self.push(Command::WorkDir(dir.to_string())),
+            ["export", assignment] => {
+                let (key, value) = split_assignment(assignment)?;
+                self.vars.insert(key.to_string(), value.to_string());
+                self.export(key, value)?;
+            }
+            ["export", ..] => bail!("export one variable per block"),
+            [] => bail!("empty command"),
+            ["sudo", ..] => {
+                simple.words.remove(0);
+                self.run(simple.render())?;
+            }
+            _ => self.run(simple.render())?,
+        }
+
+        Ok(())
+    }
+
+    fn build_system(&mut self, command: Command) {
+        self.package.build.push(command);
+        self.built = true;
+    }
+
+    fn run(&mut self, line: String) -> Result<(), Error> {
+        self.push(Command::Run(line));
+        Ok(())
+    }
+
+    fn push(&mut self, command: Command) {
+        match self.built {
+            false => self.package.build.push(command),
+            true => self.package.install.push(command),
+        }
+    }
+
+    /// Some variables describe the package, instead of the build.
+    fn export(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let package = &mut self.package;
+        let words = || value.split_whitespace().map(|s| s.to_string());
+        match key {
+            "NAME" => package.name = value.to_string(),
+            "VERSION" => {
+                check_version(value)?;
+                package.version = value.to_string();
+            }
+            "DEPENDS" => self.dep.extend(
+                value
+                    .split(',')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_string()),
+            ),
+            "INCLUDE" => self.include_files.extend(words()),
+            "EXCLUDE" => self.exclude_files.extend(words()),
+            _ => self.push(Command::Env {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+        }
+        Ok(())
+    }
+}
+
+fn owned(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+fn split_assignment(assignment: &str) -> Result<(&str, &str), Error> {
+    let eq = assignment
+        .find('=')
+        .ok_or_else(|| anyhow!("expected KEY=VALUE, not {:?}", assignment))?;
+    Ok((&assignment[..eq], &assignment[eq + 1..]))
+}
+
+/// Render a word such that `sh` will read it back unchanged.
+pub fn quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        return word.to_string();
+    }
+
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
 #[test]
 fn tokenizer() {
+    let simple = tokens("foo \\\n  bar 'baz quux' 'baz potato'").unwrap();
+    let simple = simple.single().unwrap();
     assert_eq!(
         vec!["foo", "bar", "baz quux", "baz potato"],
-        tokens("foo \\\n  bar 'baz quux' 'baz potato'").unwrap()
+        simple.words.iter().map(|w| w.text()).collect::<Vec<_>>()
     )
 }
 
+#[test]
+fn lists_and_expansion() {
+    let mut vars = Vars::new();
+    vars.insert("PREFIX".to_string(), "/usr/local".to_string());
+
+    let list =
+        tokens(r#"cd x && FOO=1 make PREFIX="$PREFIX/lib" 2>/dev/null | tee log *.o"#).unwrap();
+    assert_eq!(1, list.rest.len());
+    assert_eq!(Connector::And, list.rest[0].0);
+    assert_eq!(2, list.rest[0].1.commands.len());
+
+    assert_eq!(
+        "cd x && FOO=1 make PREFIX=/usr/local/lib 2>/dev/null | tee log *.o",
+        list.expand(&vars).unwrap().render()
+    );
+
+    assert!(list.expand(&Vars::new()).is_err());
+}
+
+#[test]
+fn assignments() {
+    let package = interpret(
+        "test",
+        load(
+            r#"source fappa-v1.bash
+
+PREFIX=/opt/foo
+
+export CFLAGS="-O2 -I$PREFIX/include"
+
+fappa-cmake
+
+sudo make install DESTDIR="$PREFIX"
+
+fappa-package
+"#,
+        )
+        .unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        vec![
+            Command::Env {
+                key: "CFLAGS".to_string(),
+                value: "-O2 -I/opt/foo/include".to_string()
+            },
+            Command::CMake
+        ],
+        package.build
+    );
+    assert_eq!(
+        vec![Command::Run("make install DESTDIR=/opt/foo".to_string())],
+        package.install
+    );
+}
+
 #[test]
 fn splitting() {
     assert_eq!(
@@ -130,5 +708,33 @@ baz \
 
 #[test]
 fn full_load() {
-    load(include_str!("../specs/sigrok.sh")).unwrap();
+    load(include_str!("../examples/sigrok.sh")).unwrap();
+}
+
+#[test]
+fn interpret_sigrok() {
+    let package = interpret("sigrok", load(include_str!("../examples/sigrok.sh")).unwrap()).unwrap();
+    assert_eq!("sigrok", package.name);
+    assert!(package.build_dep.contains(&"libusb-1.0-0-dev".to_string()));
+    assert!(!package.build_dep.contains(&"-y".to_string()));
+    assert_eq!(
+        vec![Command::Clone {
+            repo: "git://developer.intra2net.com/libftdi".to_string(),
+            branch: Some("master".to_string()),
+            sha: "d5c1622a2ff0c722c0dc59533748489b45774e55".to_string(),
+            dest: ".".to_string(),
+        }],
+        package.source
+    );
+    assert_eq!(vec![Command::CMake], package.build);
+    assert!(package.install.is_empty());
+    assert_eq!(vec!["a"], package.outputs[0].dep);
+}
+
+#[test]
+fn quoting() {
+    assert_eq!("foo", quote("foo"));
+    assert_eq!("''", quote(""));
+    assert_eq!("'foo bar'", quote("foo bar"));
+    assert_eq!(r"'it'\''s'", quote("it's"));
 }
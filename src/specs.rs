@@ -1,40 +1,200 @@
 use std::fs;
 use std::path::Path;
+use std::path::PathBuf;
 
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::ensure;
-use anyhow::Error;
 use anyhow::Context;
+use anyhow::Error;
+use serde_derive::Deserialize;
+use url::Url;
 use walkdir;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+use crate::patterns::Patterns;
+
+/// The on-disk form of a spec file: a list of `[[package]]` tables.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecFile {
+    #[serde(default)]
+    package: Vec<PackageSerialisation>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PackageSerialisation {
-    pub commands: Vec<Command>,
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+
+    #[serde(default)]
+    pub build_dep: Vec<String>,
+    #[serde(default)]
+    pub dep: Vec<String>,
+
+    #[serde(default)]
+    pub source: Vec<String>,
+    #[serde(default)]
+    pub build: Vec<String>,
+    #[serde(default)]
+    pub install: Vec<String>,
+
+    #[serde(default)]
+    pub include_files: Vec<String>,
+    #[serde(default)]
+    pub exclude_files: Vec<String>,
+
+    /// If present, replaces the top-level `dep` and `include_files`.
+    #[serde(default)]
+    pub output: Vec<OutputSerialisation>,
+}
+
+/// A `[[package.output]]` table.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSerialisation {
+    pub name: String,
+    #[serde(default)]
+    pub dep: Vec<String>,
+    #[serde(default)]
+    pub include_files: Vec<String>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Package {
-    pub ser: PackageSerialisation,
+    pub name: String,
+    /// The upstream part of the Debian version; the release is appended when packaging.
+    pub version: String,
+    /// The directory the spec was loaded from; `LOCAL` paths are relative to this.
+    pub dir: PathBuf,
+
+    pub build_dep: Vec<String>,
+
+    pub source: Vec<Command>,
+    pub build: Vec<Command>,
+    pub install: Vec<Command>,
+
+    pub exclude_files: Patterns,
+
+    /// The binary packages produced; every installed file belongs to exactly one.
+    pub outputs: Vec<Output>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Output {
+    pub name: String,
+    pub dep: Vec<String>,
+    pub include_files: Patterns,
 }
 
 impl Package {
-    fn from_str(ser: PackageSerialisation) -> Result<Package, Error> {
-        Ok(Package { ser })
+    pub fn new<S: ToString, P: Into<PathBuf>>(name: S, dir: P) -> Package {
+        Package {
+            name: name.to_string(),
+            version: "0".to_string(),
+            dir: dir.into(),
+            build_dep: Vec::new(),
+            source: Vec::new(),
+            build: Vec::new(),
+            install: Vec::new(),
+            exclude_files: Patterns::default(),
+            outputs: vec![Output::new(name)],
+        }
     }
 
-    pub fn exclude_files(&self) -> ! {
-        unimplemented!()
+    fn from_ser(ser: PackageSerialisation, dir: &Path) -> Result<Package, Error> {
+        ensure!(!ser.name.is_empty(), "package name must not be empty");
+        let version = ser.version.unwrap_or_else(|| "0".to_string());
+        check_version(&version)?;
+
+        let outputs = if ser.output.is_empty() {
+            vec![Output {
+                name: ser.name.to_string(),
+                dep: ser.dep,
+                include_files: Patterns::new(ser.include_files)
+                    .with_context(|| anyhow!("in `include_files`"))?,
+            }]
+        } else {
+            ensure!(
+                ser.dep.is_empty() && ser.include_files.is_empty(),
+                "with [[package.output]]s, `dep` and `include_files` belong in each output"
+            );
+            ser.output
+                .into_iter()
+                .map(Output::from_ser)
+                .collect::<Result<_, Error>>()?
+        };
+
+        let install = parse_commands(ser.install).with_context(|| anyhow!("in `install`"))?;
+        if let Some(command) = install.iter().find(|c| c.is_build_system()) {
+            bail!(
+                "{:?} belongs in `build`; its install step runs automatically",
+                command
+            );
+        }
+
+        Ok(Package {
+            dir: dir.to_path_buf(),
+            source: parse_commands(ser.source).with_context(|| anyhow!("in `source`"))?,
+            build: parse_commands(ser.build).with_context(|| anyhow!("in `build`"))?,
+            install,
+            name: ser.name,
+            version,
+            build_dep: ser.build_dep,
+            exclude_files: Patterns::new(ser.exclude_files)
+                .with_context(|| anyhow!("in `exclude_files`"))?,
+            outputs,
+        })
     }
-    pub fn source(&self) -> Vec<Command> {
-        unimplemented!()
+}
+
+/// A Debian upstream version: starts with a digit, and has no spaces or revision.
+pub fn check_version(version: &str) -> Result<(), Error> {
+    ensure!(
+        version.starts_with(|c: char| c.is_ascii_digit())
+            && version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".+~:".contains(c)),
+        "invalid version: {:?}",
+        version
+    );
+    Ok(())
+}
+
+impl Output {
+    pub fn new<S: ToString>(name: S) -> Output {
+        Output {
+            name: name.to_string(),
+            dep: Vec::new(),
+            include_files: Patterns::default(),
+        }
+    }
+
+    fn from_ser(ser: OutputSerialisation) -> Result<Output, Error> {
+        let OutputSerialisation {
+            name,
+            dep,
+            include_files,
+        } = ser;
+        ensure!(!name.is_empty(), "output name must not be empty");
+        let include_files = Patterns::new(include_files)
+            .with_context(|| anyhow!("in `include_files` for output {:?}", name))?;
+        Ok(Output {
+            name,
+            dep,
+            include_files,
+        })
     }
 }
 
 fn parse_commands(v: Vec<String>) -> Result<Vec<Command>, Error> {
-    v.into_iter().map(parse_command).collect()
+    v.into_iter()
+        .map(|cmd| parse_command(&cmd).with_context(|| anyhow!("parsing {:?}", cmd)))
+        .collect()
 }
 
-fn parse_command<S: AsRef<str>>(cmd: S) -> Result<Command, Error> {
+pub fn parse_command<S: AsRef<str>>(cmd: S) -> Result<Command, Error> {
     let cmd = cmd.as_ref();
 
     ensure!(
@@ -46,12 +206,19 @@ fn parse_command<S: AsRef<str>>(cmd: S) -> Result<Command, Error> {
 
     Ok(match op {
         "CLONE" => {
-            let mut args = args.split(' ');
-            Command::Clone {
-                repo: args.next().unwrap().to_string(),
-                branch: args.next().unwrap().to_string(),
-                sha: args.next().unwrap().to_string(),
-                dest: args.next().unwrap().to_string(),
+            let args: Vec<&str> = args.split_whitespace().collect();
+            match args.as_slice() {
+                [url, dest] => parse_clone_url(url, dest)?,
+                [repo, branch, sha, dest] => Command::Clone {
+                    repo: repo.to_string(),
+                    branch: Some(branch.to_string()),
+                    sha: sha.to_string(),
+                    dest: dest.to_string(),
+                },
+                _ => bail!(
+                    "usage: CLONE url?rev=sha dest, or CLONE url branch sha dest: {:?}",
+                    args
+                ),
             }
         }
         "AUTORECONF" => {
@@ -62,12 +229,101 @@ fn parse_command<S: AsRef<str>>(cmd: S) -> Result<Command, Error> {
             ensure!(args.is_empty(), "cmake takes no arguments: {:?}", args);
             Command::CMake
         }
-        //"WORKDIR" => Command::WorkDir(args.to_string()),
-        //"RUN" => Command::Run(args.to_string()),
+        "FETCH" => {
+            let args: Vec<&str> = args.split_whitespace().collect();
+            match args.as_slice() {
+                [url, checksum, dest] if checksum.starts_with("sha256=") => {
+                    let sha256 = &checksum["sha256=".len()..];
+                    ensure!(
+                        64 == sha256.len() && sha256.chars().all(|c| c.is_ascii_hexdigit()),
+                        "sha256 must be 64 hex characters: {:?}",
+                        sha256
+                    );
+                    Command::Fetch {
+                        url: Url::parse(url)?.to_string(),
+                        sha256: sha256.to_ascii_lowercase(),
+                        dest: dest.to_string(),
+                    }
+                }
+                _ => bail!("usage: FETCH url sha256=... dest: {:?}", args),
+            }
+        }
+        "LOCAL" => {
+            let args: Vec<&str> = args.split_whitespace().collect();
+            match args.as_slice() {
+                [path, dest] => Command::Local {
+                    path: path.to_string(),
+                    dest: dest.to_string(),
+                },
+                _ => bail!("usage: LOCAL path dest: {:?}", args),
+            }
+        }
+        "PATCHES" => {
+            let args: Vec<&str> = args.split_whitespace().collect();
+            match args.as_slice() {
+                [dir, dest] => Command::Patches {
+                    dir: dir.to_string(),
+                    dest: dest.to_string(),
+                },
+                _ => bail!("usage: PATCHES dir dest: {:?}", args),
+            }
+        }
+        "MESON" => Command::Meson { args: words(args) },
+        "MAKE" => Command::Make { args: words(args) },
+        "CARGO" => Command::Cargo { args: words(args) },
+        "SETUP_PY" => Command::SetupPy { args: words(args) },
+        "WORKDIR" => {
+            ensure!(!args.is_empty(), "workdir needs a directory");
+            Command::WorkDir(args.to_string())
+        }
+        "RUN" => {
+            ensure!(!args.is_empty(), "run needs a command");
+            Command::Run(args.to_string())
+        }
+        "ENV" => {
+            let eq = args
+                .find('=')
+                .ok_or_else(|| anyhow!("usage: ENV KEY=VALUE: {:?}", args))?;
+            let (key, value) = (&args[..eq], &args[eq + 1..]);
+            ensure!(
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || '_' == c),
+                "invalid variable name: {:?}",
+                key
+            );
+            Command::Env {
+                key: key.to_string(),
+                value: value.to_string(),
+            }
+        }
         other => bail!("unrecognised op: {:?}", other),
     })
 }
 
+/// `git://example.com/foo?rev=abcd`, the form `git::check_cloned` also understands.
+fn parse_clone_url(url: &str, dest: &str) -> Result<Command, Error> {
+    let mut url = Url::parse(url)?;
+    let sha = {
+        let mut args = url.query_pairs();
+        ensure!(1 == args.count(), "there must be exactly one arg, `rev`");
+        match args.next().unwrap() {
+            (ref mode, ref value) if mode == "rev" => value.to_string(),
+            (other, _) => bail!("unsupported clone mode: {}", other),
+        }
+    };
+    url.set_query(None);
+
+    Ok(Command::Clone {
+        repo: url.to_string(),
+        branch: None,
+        sha,
+        dest: dest.to_string(),
+    })
+}
+
+fn words(args: &str) -> Vec<String> {
+    args.split_whitespace().map(|s| s.to_string()).collect()
+}
+
 fn split_space(s: &str) -> (&str, &str) {
     match s.find(' ') {
         Some(p) => {
@@ -78,22 +334,87 @@ fn split_space(s: &str) -> (&str, &str) {
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Phase {
+    Source,
+    Build,
+    Install,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Command {
     Clone {
         repo: String,
-        branch: String,
+        /// Only necessary if `sha` isn't reachable from the default refspec.
+        branch: Option<String>,
         sha: String,
         dest: String,
     },
+    /// A tarball, unpacked into `dest`.
+    Fetch {
+        url: String,
+        sha256: String,
+        dest: String,
+    },
+    /// A directory next to the spec, copied into `dest`.
+    Local {
+        path: String,
+        dest: String,
+    },
+    /// A directory next to the spec containing a quilt-style `series` file, and the patches
+    /// it lists; applied, in order, to the already extracted source at `dest`.
+    Patches {
+        dir: String,
+        dest: String,
+    },
     Autoreconf,
     CMake,
+    /// `args` are passed to `meson` when configuring, e.g. `--buildtype=release`
+    Meson {
+        args: Vec<String>,
+    },
+    /// `args` are passed to both `make` and `make install`, e.g. `PREFIX=/usr/local`
+    Make {
+        args: Vec<String>,
+    },
+    /// `args` are passed to both `cargo build` and `cargo install`, e.g. `--features foo`
+    Cargo {
+        args: Vec<String>,
+    },
+    /// `args` are passed to `setup.py build`
+    SetupPy {
+        args: Vec<String>,
+    },
+    /// Subsequent steps run here; relative paths are relative to the previous `WorkDir`.
+    WorkDir(String),
+    /// A line of shell, run as-is.
+    Run(String),
+    /// Subsequent steps run with this variable set.
+    Env {
+        key: String,
+        value: String,
+    },
+}
+
+impl Command {
+    /// Build systems have a step in the install phase, too, which runs automatically.
+    pub fn is_build_system(&self) -> bool {
+        match self {
+            Command::Autoreconf
+            | Command::CMake
+            | Command::Meson { .. }
+            | Command::Make { .. }
+            | Command::Cargo { .. }
+            | Command::SetupPy { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 pub fn load_from<P: AsRef<Path>>(dir: P) -> Result<Vec<Package>, Error> {
     let mut ret = Vec::new();
 
-    for entry in walkdir::WalkDir::new(dir) {
+    for entry in walkdir::WalkDir::new(dir).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
         let entry = entry?;
 
         if entry.file_type().is_dir() {
@@ -109,32 +430,262 @@ pub fn load_from<P: AsRef<Path>>(dir: P) -> Result<Vec<Package>, Error> {
             continue;
         }
 
+        let load: fn(&Path, &str) -> Result<Vec<Package>, Error> =
+            match entry.path().extension().and_then(|e| e.to_str()) {
+                Some("toml") => load_toml,
+                Some("sh") => load_sh,
+                _ => continue,
+            };
+
         let text =
             fs::read(entry.path()).with_context(|| format!("opening {:?}", entry.path()))?;
         let text = String::from_utf8(text)?;
-        let commands = blocks(&text)
-            .into_iter()
-            .map(parse_command)
-            .collect::<Result<Vec<_>, Error>>()
-            .with_context(|| format!("parsing an entry in {:?}", entry.path()))?;
-
-        ret.push(Package {
-            ser: PackageSerialisation { commands },
-        });
+        ret.extend(
+            load(entry.path(), &text).with_context(|| format!("loading {:?}", entry.path()))?,
+        );
     }
 
     Ok(ret)
 }
 
-fn blocks(text: &str) -> Vec<String> {
-    let text: Vec<_> = text
-        .lines()
-        .map(|l| l.trim_end())
-        .filter(|l| !l.starts_with('#'))
-        .collect();
+fn load_sh(path: &Path, text: &str) -> Result<Vec<Package>, Error> {
+    let name = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow!("non-utf-8 spec name: {:?}", path))?;
+    let mut package = crate::spec_sh::interpret(name, crate::spec_sh::load(text)?)?;
+    package.dir = spec_dir(path);
+    Ok(vec![package])
+}
+
+fn load_toml(path: &Path, text: &str) -> Result<Vec<Package>, Error> {
+    let file: SpecFile = toml::from_str(text)?;
+    let dir = spec_dir(path);
 
-    text.split(|l| l.is_empty())
-        .filter(|b| !b.is_empty())
-        .map(|lines| lines.join("\n"))
+    file.package
+        .into_iter()
+        .enumerate()
+        .map(|(i, ser)| {
+            let name = ser.name.clone();
+            Package::from_ser(ser, &dir)
+                .with_context(|| format!("in [[package]] #{} ({:?})", i + 1, name))
+        })
         .collect()
 }
+
+fn spec_dir(path: &Path) -> PathBuf {
+    path.parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Command;
+
+    #[test]
+    fn toml_package() {
+        let packages = super::load_toml(
+            Path::new("specs/test.toml"),
+            r#"
+[[package]]
+name = "foo"
+build_dep = ["cmake"]
+source = ["CLONE git://example.com/foo?rev=abcd foo"]
+build = ["CMAKE"]
+include_files = ["/usr/local/**"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(1, packages.len());
+        let foo = &packages[0];
+        assert_eq!("foo", foo.name);
+        assert_eq!("0", foo.version);
+        assert_eq!(vec!["cmake"], foo.build_dep);
+        assert_eq!(1, foo.outputs.len());
+        assert_eq!("foo", foo.outputs[0].name);
+        assert!(foo.outputs[0].dep.is_empty());
+        assert_eq!(
+            vec![Command::Clone {
+                repo: "git://example.com/foo".to_string(),
+                branch: None,
+                sha: "abcd".to_string(),
+                dest: "foo".to_string(),
+            }],
+            foo.source
+        );
+        assert_eq!(vec![Command::CMake], foo.build);
+        assert!(foo.install.is_empty());
+        assert_eq!(
+            vec!["/usr/local/**"],
+            foo.outputs[0].include_files.sources()
+        );
+    }
+
+    #[test]
+    fn error_names_table() {
+        let err = super::load_toml(
+            Path::new("specs/test.toml"),
+            r#"
+[[package]]
+name = "fine"
+
+[[package]]
+name = "broken"
+build = ["FROB"]
+"#,
+        )
+        .unwrap_err();
+
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("#2"), "{}", msg);
+        assert!(msg.contains("broken"), "{}", msg);
+        assert!(msg.contains("FROB"), "{}", msg);
+    }
+
+    #[test]
+    fn sigrok() {
+        let packages = super::load_toml(
+            Path::new("specs/sigrok.toml"),
+            include_str!("../specs/sigrok.toml"),
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["libftdi1-dev", "libsigrok"],
+            packages.iter().map(|p| p.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Command::WorkDir("libftdi".to_string()), Command::CMake],
+            packages[0].build
+        );
+        assert!(packages[0].install.is_empty());
+
+        assert!(super::load_toml(
+            Path::new("specs/test.toml"),
+            "[[package]]\nname = 'a'\nbuild = ['CMAKE']\ninstall = ['CMAKE']\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn env() {
+        assert_eq!(
+            Command::Env {
+                key: "CFLAGS".to_string(),
+                value: "-O2 -g".to_string()
+            },
+            super::parse_command("ENV CFLAGS=-O2 -g").unwrap()
+        );
+        assert!(super::parse_command("ENV -O2").is_err());
+    }
+
+    #[test]
+    fn fetch() {
+        let sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        assert_eq!(
+            Command::Fetch {
+                url: "https://example.com/foo-1.0.tar.gz".to_string(),
+                sha256: sha256.to_string(),
+                dest: "foo".to_string(),
+            },
+            super::parse_command(format!(
+                "FETCH https://example.com/foo-1.0.tar.gz sha256={} foo",
+                sha256.to_ascii_uppercase()
+            ))
+            .unwrap()
+        );
+        assert!(
+            super::parse_command("FETCH https://example.com/foo.tar.gz sha256=abc foo").is_err()
+        );
+        assert!(super::parse_command("FETCH https://example.com/foo.tar.gz foo").is_err());
+    }
+
+    #[test]
+    fn build_systems() {
+        assert_eq!(
+            Command::Meson {
+                args: vec![
+                    "--buildtype=release".to_string(),
+                    "-Dpython=false".to_string()
+                ]
+            },
+            super::parse_command("MESON --buildtype=release  -Dpython=false").unwrap()
+        );
+        assert_eq!(
+            Command::Cargo { args: Vec::new() },
+            super::parse_command("CARGO").unwrap()
+        );
+    }
+
+    #[test]
+    fn outputs() {
+        let packages = super::load_toml(
+            Path::new("specs/test.toml"),
+            r#"
+[[package]]
+name = "libfoo"
+
+[[package.output]]
+name = "libfoo1"
+include_files = ["/usr/local/lib/*.so.*"]
+
+[[package.output]]
+name = "libfoo-dev"
+dep = ["libfoo1"]
+include_files = ["/usr/local/include/**", "/usr/local/lib/*.so"]
+"#,
+        )
+        .unwrap();
+
+        let outputs = &packages[0].outputs;
+        assert_eq!(
+            vec!["libfoo1", "libfoo-dev"],
+            outputs.iter().map(|o| o.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["libfoo1"], outputs[1].dep);
+
+        assert!(super::load_toml(
+            Path::new("specs/test.toml"),
+            "[[package]]\nname = 'a'\ndep = ['b']\n[[package.output]]\nname = 'c'\n",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn version() {
+        let load = |version: &str| {
+            super::load_toml(
+                Path::new("specs/test.toml"),
+                &format!("[[package]]\nname = 'a'\nversion = '{}'\n", version),
+            )
+        };
+        assert_eq!(
+            "1.2+git20200101",
+            load("1.2+git20200101").unwrap()[0].version
+        );
+        assert!(load("v1.2").is_err());
+        assert!(load("1.2-1").is_err());
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let err = super::load_toml(
+            Path::new("specs/test.toml"),
+            "[[package]]\nname = 'a'\nexclude_files = ['/build/[**']\n",
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("exclude_files"), "{:#}", err);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert!(super::load_toml(
+            Path::new("specs/test.toml"),
+            "[[package]]\nname = 'a'\nbuild_deps = []\n"
+        )
+        .is_err());
+    }
+}